@@ -6,9 +6,11 @@ use x86_64::VirtualAddress;
 
 use spin::Once;
 
+use io::apic;
 use memory::MemoryController;
 
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
+const PAGE_FAULT_IST_INDEX: usize = 1;
 
 lazy_static! {
     static ref IDT: Idt = {
@@ -21,7 +23,15 @@ lazy_static! {
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
         }
 
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        // on its own stack, so overflowing a kernel stack into its guard page can be reported
+        // rather than turning into a double fault
+        unsafe {
+            idt.page_fault.set_handler_fn(page_fault_handler)
+                .set_stack_index(PAGE_FAULT_IST_INDEX as u16);
+        }
+
+        idt[apic::TIMER_VECTOR as usize].set_handler_fn(timer_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_handler);
 
         idt
     };
@@ -39,9 +49,13 @@ pub fn init(memory_controller: &mut MemoryController) {
     let double_fault_stack = memory_controller.alloc_stack(1)
         .expect("could not allocate double fault stack");
 
+    let page_fault_stack = memory_controller.alloc_stack(4)
+        .expect("could not allocate page fault stack");
+
     let tss = TSS.call_once(|| {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = VirtualAddress(double_fault_stack.top());
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = VirtualAddress(double_fault_stack.top().as_usize());
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = VirtualAddress(page_fault_stack.top().as_usize());
 
         tss
     });
@@ -69,12 +83,37 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFra
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn timer_handler(_stack_frame: &mut ExceptionStackFrame) {
+    // skip the sample if the interrupted code holds the controller; the next tick will get it
+    if let Some(mut controller) = ::memory::controller().try_lock() {
+        controller.sample_working_sets();
+    }
+
+    apic::eoi();
+}
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: &mut ExceptionStackFrame) {}
+
 extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
     println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
     unsafe { ::x86_64::instructions::halt() };
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut ExceptionStackFrame, _error_code: PageFaultErrorCode) {
-    println!("PAGE FAULT caused by access at {:#x}\n{:#?}", ::x86_64::registers::control_regs::cr2(), stack_frame);
+extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: PageFaultErrorCode) {
+    use memory::{self, FaultFlags, VirtAddr};
+
+    let addr = ::x86_64::registers::control_regs::cr2();
+
+    if let Some(stack) = memory::overflowed_stack(VirtAddr::new(addr.0)) {
+        println!("EXCEPTION: stack overflow in stack {} ({:#x}..{:#x}) at RIP {:#x}",
+            stack.id, stack.bottom, stack.top, stack_frame.instruction_pointer.0);
+        unsafe { ::x86_64::instructions::halt() };
+    }
+
+    if memory::handle_page_fault(VirtAddr::new(addr.0), FaultFlags::from_bits_truncate(error_code.bits())) {
+        return;
+    }
+
+    println!("PAGE FAULT caused by access at {:#x}\n{:#?}", addr, stack_frame);
     unsafe { ::x86_64::instructions::halt() };
 }
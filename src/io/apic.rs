@@ -1,11 +1,29 @@
 use cpuid::CpuId;
-use lateinit::LateInit;
+use spin::Once;
 use x86_64::registers::msr::{rdmsr, wrmsr, IA32_APIC_BASE};
 
-use memory::{PhysicalAddr, VirtualAddr};
+use memory::{self, CacheType, MmioRegion, PhysAddr, PAGE_SIZE};
+use super::outb;
 
-pub const APIC_PHYS: PhysicalAddr = 0xfee0_0000;
-pub static APIC_VIRT: LateInit<VirtualAddr> = LateInit::new();  // initialized in memory::init
+pub const APIC_PHYS: PhysAddr = PhysAddr::new_unchecked(0xfee0_0000);
+
+pub const TIMER_VECTOR: u8 = 0x30;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const EOI: usize = 0xb0;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const LVT_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+static APIC: Once<MmioRegion> = Once::new();  // initialized in setup_apic
+
+#[inline]
+fn apic() -> &'static MmioRegion {
+    APIC.try().expect("APIC is not mapped")
+}
 
 pub fn setup_apic() {
     let cpu_info = CpuId::new();
@@ -18,7 +36,7 @@ pub fn setup_apic() {
     unsafe {
         let apic_base = rdmsr(IA32_APIC_BASE);
 
-        assert_eq!(apic_base & 0xffffff000, APIC_PHYS as u64);
+        assert_eq!(apic_base & 0xffffff000, APIC_PHYS.as_u64());
 
         if apic_base & (1 << 11) == 0 {
             println!("NOTE: APIC was disabled in MSR, enabling it");
@@ -26,13 +44,43 @@ pub fn setup_apic() {
         }
     }
 
-    let sivr = SIVR::get();
+    // the registers need to be mapped strong uncacheable (UC), see 11-35, Vol. 3A
+    let region = APIC.call_once(|| {
+        memory::map_mmio(APIC_PHYS, PAGE_SIZE, CacheType::Uncacheable).expect("unable to map APIC")
+    });
+
+    println!("mapped APIC to {:#x}", region.base());
+
+    let mut sivr = SIVR::read();
     if !sivr.apic_enabled() {
         println!("APIC was disabled by SIVR, enabling it");
         unsafe { sivr.enable_apic(); }
     }
 
-    sivr.set_spurious_vector(0xff);
+    sivr.set_spurious_vector(SPURIOUS_VECTOR);
+    sivr.write();
+
+    // the legacy PIC delivers on the exception vectors until it's remapped. we don't use it, so
+    // mask every line before anything enables interrupts
+    unsafe {
+        outb(0x21, 0xff);
+        outb(0xa1, 0xff);
+    }
+}
+
+/// Fire `TIMER_VECTOR` every `ticks` × 16 bus clock ticks. The bus clock isn't calibrated, so
+/// the period in wall time depends on the machine.
+pub fn start_timer(ticks: u32) {
+    let apic = apic();
+
+    apic.write32(TIMER_DIVIDE, DIVIDE_BY_16);
+    apic.write32(LVT_TIMER, LVT_PERIODIC | TIMER_VECTOR as u32);
+    apic.write32(TIMER_INITIAL_COUNT, ticks);
+}
+
+/// Signal the end of the interrupt being handled. Not for spurious interrupts.
+pub fn eoi() {
+    apic().write32(EOI, 0);
 }
 
 pub unsafe fn enable_apic() {
@@ -42,23 +90,31 @@ pub unsafe fn enable_apic() {
         wrmsr(IA32_APIC_BASE, apic_base | 1 << 11);
     }
 
-    SIVR::get().enable_apic()
+    let mut sivr = SIVR::read();
+    sivr.enable_apic();
+    sivr.write();
 }
 
 pub unsafe fn disable_apic() {
-    SIVR::get().disable_apic()
+    let mut sivr = SIVR::read();
+    sivr.disable_apic();
+    sivr.write();
 }
 
 /// Spurious interrupt vector register layout
 pub struct SIVR(u32);
 
 impl SIVR {
-    const OFFSET: usize = 0xf0; // offset from APIC_VIRT
+    const OFFSET: usize = 0xf0; // offset into the APIC's registers
 
     #[inline]
-    pub fn get() -> &'static mut Self {
-        let ptr = (*APIC_VIRT + Self::OFFSET) as *mut SIVR;
-        unsafe { ptr.as_mut().unwrap() }
+    pub fn read() -> Self {
+        SIVR(apic().read32(Self::OFFSET))
+    }
+
+    #[inline]
+    pub fn write(&self) {
+        apic().write32(Self::OFFSET, self.0)
     }
 
     #[inline]
@@ -68,7 +124,7 @@ impl SIVR {
 
     #[inline]
     pub fn set_spurious_vector(&mut self, val: u8) {
-        self.0 |= val as u32
+        self.0 = (self.0 & !0xff) | val as u32
     }
 
     #[inline]
@@ -88,11 +144,11 @@ impl SIVR {
 
     #[inline]
     unsafe fn disable_apic(&mut self) {
-        self.0 |= 1 << 8;
+        self.0 &= !(1 << 8);
     }
 
     #[inline]
     unsafe fn enable_apic(&mut self) {
-        self.0 &= !(1 << 8);
+        self.0 |= 1 << 8;
     }
 }
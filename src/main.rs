@@ -1,5 +1,6 @@
-#![no_std]
-#![no_main]
+// unit tests build for the host, on top of std
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 #![feature(lang_items)]
 #![feature(asm)]
@@ -15,6 +16,7 @@
 // TODO: remove
 #![allow(dead_code)]
 
+#[cfg(test)] extern crate core;
 #[allow(unused_imports)] #[macro_use] extern crate alloc;
 #[allow(unused_imports)] #[macro_use] extern crate itertools;
 #[allow(unused_imports)] #[macro_use] extern crate failure;
@@ -26,7 +28,7 @@
 extern crate fixedvec;
 extern crate bit_field;
 extern crate linked_list_allocator;
-extern crate rlibc;
+#[cfg(not(test))] extern crate rlibc;
 extern crate spin;
 extern crate volatile;
 extern crate x86_64;
@@ -42,7 +44,7 @@ mod memory;
 mod interrupts;
 mod io;
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 pub static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 fn enable_syscall() {
@@ -54,19 +56,49 @@ fn enable_syscall() {
     }
 }
 
+/// Size of the stack the kernel moves to once memory is up.
+const KERNEL_STACK_PAGES: usize = 16;
+
+/// LAPIC timer period for working set sampling, in units of 16 bus clock ticks.
+const WORKING_SET_TIMER_TICKS: u32 = 0x10_0000;
+
 #[no_mangle]
 pub extern "C" fn osiris_main() -> ! {
     vga_buffer::clear_screen();
 
-    let mut memory_controller = memory::init();
+    let memory_controller = memory::init();
+    vga_buffer::remap();
+
+    // the bootloader's stack is identity mapped in the lower half, which belongs to user space
+    let stack = memory_controller.lock().alloc_stack(KERNEL_STACK_PAGES).expect("unable to allocate kernel stack");
+    let top = stack.top().as_usize();
+    core::mem::forget(stack);
+
+    unsafe { switch_stack(top, kernel_main) }
+}
 
-    interrupts::init(&mut memory_controller);
+/// Continue in `f` on the stack ending at `top`. The current stack is abandoned.
+unsafe fn switch_stack(top: usize, f: extern "C" fn() -> !) -> ! {
+    asm!("mov $0, %rsp
+          xor %rbp, %rbp
+          call *$1" :: "r"(top), "r"(f as usize) : "memory" : "volatile");
+    unreachable!()
+}
+
+extern "C" fn kernel_main() -> ! {
+    let memory_controller = memory::controller();
+
+    interrupts::init(&mut memory_controller.lock());
     memory::extend_heap();
 
     enable_syscall();
 
     io::apic::setup_apic();
 
+    // sample working sets periodically
+    io::apic::start_timer(WORKING_SET_TIMER_TICKS);
+    unsafe { x86_64::instructions::interrupts::enable(); }
+
     use io::ScanCode;
 
     fn read_one() -> ScanCode {
@@ -99,6 +131,7 @@ pub extern "C" fn osiris_main() -> ! {
     }
 }
 
+#[cfg(not(test))]
 #[lang = "panic_fmt"]
 #[no_mangle]
 pub extern fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
@@ -109,6 +142,7 @@ pub extern fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32
     loop {}
 }
 
+#[cfg(not(test))]
 #[lang = "oom"]
 #[no_mangle]
 pub extern fn oom() -> ! {
@@ -0,0 +1,214 @@
+use super::{controller, FrameAllocator, MemoryController, PhysAddr, Frame};
+use super::paging::{CacheType, EntryFlags, InactivePageTable, MapError, MapperFlush, Page, Pcid, USER_ACCESSIBLE};
+use super::paging::pcid;
+
+/// Whether P4 entry `index` is shared with the kernel rather than owned by an address space.
+/// The recursive entry is neither. Nothing the kernel uses after boot (its stacks, the VGA
+/// buffer) lives in the lower half, so all of it belongs to the address space.
+fn is_kernel_entry(index: usize) -> bool {
+    index >= 256 && index < 511
+}
+
+/// A set of user mappings on top of the shared kernel half.
+///
+/// The kernel's P4 entries are copied in when the address space is created, so kernel-half
+/// tables are shared and anything mapped there later is visible everywhere. User pages can be
+/// mapped and unmapped whether or not the address space is active.
+///
+/// Each address space gets its own PCID when the CPU supports them, so switching between them
+/// keeps their TLB entries.
+///
+/// All operations lock the global `MemoryController`, so none of them (including `drop`) may be
+/// called with it held.
+pub struct AddressSpace {
+    table: InactivePageTable,
+    pcid: Option<Pcid>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let mut controller = controller().lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temp_page,
+            ..
+        } = &mut *controller;
+
+        let frame = frame_allocator.alloc().ok_or(MapError::OutOfFrames)?;
+        let mut table = InactivePageTable::new(frame, active_table, temp_page);
+
+        // a fixed array rather than a Vec: growing the heap needs the lock we're holding
+        let mut shared = [None; 512];
+
+        active_table.p4().iter()
+            .enumerate()
+            .filter(|&(i, _)| is_kernel_entry(i))
+            .for_each(|(i, e)| shared[i] = e.pointed_frame().map(|f| (f.start_addr(), e.flags())));
+
+        active_table.with(&mut table, temp_page, |mapper| {
+            for (i, entry) in shared.iter().enumerate() {
+                if let Some((addr, flags)) = *entry {
+                    mapper.p4_mut()[i].set(Frame::containing_addr(addr), flags);
+                    mapper.p4_mut().increment_entry_count();
+                }
+            }
+        });
+
+        Ok(AddressSpace {
+            table,
+            pcid: pcid::alloc(),
+        })
+    }
+
+    /// Map `page` to a fresh frame. `USER_ACCESSIBLE` is implied.
+    pub fn map(&mut self, page: Page, flags: EntryFlags) -> Result<(), MapError> {
+        check_user_page(page)?;
+
+        let active = self.is_active();
+        let pcid = self.pcid;
+        let mut controller = controller().lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temp_page,
+            ..
+        } = &mut *controller;
+
+        let mut result = Ok(());
+
+        active_table.with(&mut self.table, temp_page, |mapper| {
+            result = mapper.map(page, flags | USER_ACCESSIBLE, CacheType::WriteBack, frame_allocator)
+                .map(|flush| finish(flush, active, pcid));
+        });
+
+        result
+    }
+
+    /// Map `page` to `frame`, cached as `cache`. `USER_ACCESSIBLE` is implied.
+    pub fn map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags, cache: CacheType) -> Result<(), MapError> {
+        check_user_page(page)?;
+
+        let active = self.is_active();
+        let pcid = self.pcid;
+        let mut controller = controller().lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temp_page,
+            ..
+        } = &mut *controller;
+
+        let mut result = Ok(());
+
+        active_table.with(&mut self.table, temp_page, |mapper| {
+            result = mapper.map_to(page, frame, flags | USER_ACCESSIBLE, cache, frame_allocator)
+                .map(|flush| finish(flush, active, pcid));
+        });
+
+        result
+    }
+
+    /// Unmap `page`, releasing its frame if nothing else maps it.
+    pub fn unmap(&mut self, page: Page) -> Result<(), MapError> {
+        check_user_page(page)?;
+
+        let active = self.is_active();
+        let pcid = self.pcid;
+        let mut controller = controller().lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temp_page,
+            ..
+        } = &mut *controller;
+
+        let mut result = Ok(());
+
+        active_table.with(&mut self.table, temp_page, |mapper| {
+            result = mapper.unmap(page, frame_allocator)
+                .map(|flush| finish(flush, active, pcid));
+        });
+
+        result
+    }
+
+    /// Share `page` of the active address space into this one at the same address,
+    /// copy-on-write. The page must be mapped with 4 KiB pages.
+    pub fn share_cow(&mut self, page: Page) -> Result<(), MapError> {
+        check_user_page(page)?;
+
+        let pcid = self.pcid;
+        let mut controller = controller().lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temp_page,
+            ..
+        } = &mut *controller;
+
+        active_table.share_cow(page, &mut self.table, temp_page, frame_allocator)?;
+
+        // the new mapping may replace a stale one from before
+        pcid.map(|pcid| pcid::invalidate(pcid, page.start_addr()));
+        Ok(())
+    }
+
+    /// Switch to this address space. Returns the previously active table so the caller can
+    /// switch back.
+    pub fn activate(&self) -> InactivePageTable {
+        let mut controller = controller().lock();
+        let table = InactivePageTable::new_from_p4_frame(self.table.p4_frame().clone());
+
+        controller.active_table.switch_pcid(table, self.pcid.unwrap_or(Pcid::KERNEL))
+    }
+
+    pub fn is_active(&self) -> bool {
+        use x86_64::registers::control_regs;
+
+        Frame::containing_addr(PhysAddr::new(control_regs::cr3().0 as usize)) == *self.table.p4_frame()
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Release every user frame and table, then the P4 itself. Kernel-half tables are shared and
+    /// stay put.
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        let mut controller = controller().lock();
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temp_page,
+            ..
+        } = &mut *controller;
+
+        active_table.with(&mut self.table, temp_page, |mapper| {
+            (0..256)
+                .filter(|&i| !is_kernel_entry(i))
+                .for_each(|i| mapper.clear_p4_entry(i, frame_allocator));
+        });
+
+        frame_allocator.release(self.table.p4_frame().clone());
+        self.pcid.map(pcid::free);
+    }
+}
+
+/// Flush a change to an address space: directly if it's active, through its PCID if it has one,
+/// and not at all otherwise since switching to it flushes anyway.
+fn finish(flush: MapperFlush, active: bool, pcid: Option<Pcid>) {
+    match (active, pcid) {
+        (true, _) => flush.flush(),
+        (false, Some(pcid)) => flush.invalidate(pcid),
+        (false, None) => flush.ignore(),
+    }
+}
+
+fn check_user_page(page: Page) -> Result<(), MapError> {
+    if is_kernel_entry(page.p4_index()) || page.p4_index() == 511 {
+        Err(MapError::KernelAddress { addr: page.start_addr() })
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use core::slice;
+
+const BITS_PER_WORD: usize = 64;
+
+/// A fixed-length bitmap over storage the caller has mapped for it. The bitmap does not own
+/// the backing memory and never frees it.
+#[derive(Debug)]
+pub struct Bitmap {
+    words: &'static mut [u64],
+    len: usize,
+}
+
+impl Bitmap {
+    /// Build a bitmap of `len` bits backed by `storage`, clearing every bit.
+    ///
+    /// `storage` must point to at least `Bitmap::storage_size(len)` bytes of writable memory
+    /// that stays mapped for the life of the kernel.
+    pub unsafe fn new(storage: *mut u64, len: usize) -> Bitmap {
+        let words = slice::from_raw_parts_mut(storage, Self::words_for(len));
+        words.iter_mut().for_each(|w| *w = 0);
+
+        Bitmap {
+            words,
+            len,
+        }
+    }
+
+    /// Number of bytes of backing storage needed for a bitmap of `len` bits.
+    pub fn storage_size(len: usize) -> usize {
+        Self::words_for(len) * (BITS_PER_WORD / 8)
+    }
+
+    fn words_for(len: usize) -> usize {
+        (len + BITS_PER_WORD - 1) / BITS_PER_WORD
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> bool {
+        assert!(index < self.len, "bit {} out of range", index);
+        self.words[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    #[inline]
+    pub fn set(&mut self, index: usize) {
+        assert!(index < self.len, "bit {} out of range", index);
+        self.words[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    #[inline]
+    pub fn clear(&mut self, index: usize) {
+        assert!(index < self.len, "bit {} out of range", index);
+        self.words[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    /// Index of the first set bit at or after `start`, if any.
+    pub fn first_set_from(&self, start: usize) -> Option<usize> {
+        if start >= self.len {
+            return None;
+        }
+
+        let first_word = start / BITS_PER_WORD;
+        let first_mask = !0u64 << (start % BITS_PER_WORD);
+
+        self.words[first_word..].iter()
+            .enumerate()
+            .map(|(i, w)| if i == 0 { (i, w & first_mask) } else { (i, *w) })
+            .find(|&(_, w)| w != 0)
+            .map(|(i, w)| (first_word + i) * BITS_PER_WORD + w.trailing_zeros() as usize)
+            .and_then(|index| if index < self.len { Some(index) } else { None })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bitmap(len: usize) -> Bitmap {
+        let storage = Box::leak(vec![!0u64; Bitmap::words_for(len)].into_boxed_slice());
+        unsafe { Bitmap::new(storage.as_mut_ptr(), len) }
+    }
+
+    #[test]
+    fn storage_size_rounds_up_to_words() {
+        assert_eq!(Bitmap::storage_size(0), 0);
+        assert_eq!(Bitmap::storage_size(1), 8);
+        assert_eq!(Bitmap::storage_size(64), 8);
+        assert_eq!(Bitmap::storage_size(65), 16);
+    }
+
+    #[test]
+    fn new_clears_storage() {
+        let bits = bitmap(130);
+        assert!((0..130).all(|i| !bits.get(i)));
+    }
+
+    #[test]
+    fn set_and_clear() {
+        let mut bits = bitmap(130);
+
+        bits.set(0);
+        bits.set(64);
+        bits.set(129);
+        assert!(bits.get(0) && bits.get(64) && bits.get(129));
+        assert!(!bits.get(1) && !bits.get(63) && !bits.get(128));
+
+        bits.clear(64);
+        assert!(!bits.get(64));
+        assert!(bits.get(0) && bits.get(129));
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_range() {
+        bitmap(10).get(10);
+    }
+
+    #[test]
+    fn first_set_from() {
+        let mut bits = bitmap(200);
+        assert_eq!(bits.first_set_from(0), None);
+
+        bits.set(5);
+        bits.set(70);
+        bits.set(199);
+
+        assert_eq!(bits.first_set_from(0), Some(5));
+        assert_eq!(bits.first_set_from(5), Some(5));
+        assert_eq!(bits.first_set_from(6), Some(70));
+        assert_eq!(bits.first_set_from(71), Some(199));
+        assert_eq!(bits.first_set_from(200), None);
+    }
+
+    #[test]
+    fn first_set_from_ignores_bits_past_len() {
+        let bits = bitmap(70);
+        bits.words[1] |= 1 << 10; // bit 74, in the last word's padding
+        assert_eq!(bits.first_set_from(0), None);
+    }
+}
@@ -1,5 +1,5 @@
 use super::PAGE_SIZE;
-use super::paging::PhysicalAddr;
+use super::paging::PhysAddr;
 
 // TODO: see if we can remove Clone derive here
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -12,12 +12,12 @@ impl Frame {
         Frame { index }
     }
 
-    pub(crate) fn containing_addr(addr: usize) -> Frame {
-        Frame{ index: addr / PAGE_SIZE }
+    pub(crate) fn containing_addr(addr: PhysAddr) -> Frame {
+        Frame{ index: addr.as_usize() / PAGE_SIZE }
     }
 
-    pub(crate) fn start_addr(&self) -> PhysicalAddr {
-        self.index * PAGE_SIZE
+    pub(crate) fn start_addr(&self) -> PhysAddr {
+        PhysAddr::new(self.index * PAGE_SIZE)
     }
 
     pub(crate) fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
@@ -57,4 +57,11 @@ impl Iterator for FrameIter {
         self.start.index += 1;
         Some(frame)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.end.index + 1).saturating_sub(self.start.index);
+        (len, Some(len))
+    }
 }
+
+impl ExactSizeIterator for FrameIter {}
@@ -1,11 +1,22 @@
-use memory::{Frame, FrameAllocator};
+use memory::{Frame, FrameAllocator, PhysAddr};
 use memory::frame_set::FrameSetMut;
 use bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
 
+/// Most released frames kept for reuse. Releasing more than this at once panics.
+const MAX_FREE: usize = 32;
+
+/// Hands out frames from the usable regions of the memory map in address order. Released frames
+/// go onto a free list that is drained before any new frames are taken.
+///
+/// This allocator runs before the heap exists, and the frames it hands out aren't necessarily
+/// mapped, so the free list is a fixed array rather than a list threaded through the frames.
+/// Releasing a frame that was never handed out, or that is already on the free list, panics
+/// whether or not `frame_set` records allocations.
 pub struct AreaFrameAllocator<T> {
     memory_map: MemoryMap,
     next_free_frame: Frame,
     current_region: Option<MemoryRegion>,
+    free_frames: [Option<Frame>; MAX_FREE],
     frame_set: T,
 }
 
@@ -16,8 +27,9 @@ impl <T: FrameSetMut> AreaFrameAllocator<T> {
     ) -> AreaFrameAllocator<T> {
         let mut allocator = AreaFrameAllocator {
             memory_map,
-            next_free_frame: Frame::containing_addr(0),
+            next_free_frame: Frame::new(0),
             current_region: None,
+            free_frames: Default::default(),
             frame_set,
         };
 
@@ -34,30 +46,40 @@ impl <T: FrameSetMut> AreaFrameAllocator<T> {
         self.current_region = self.memory_map.iter()
             .filter(|region| {
                 let addr = region.range.end_addr() - 1;
-                region.region_type == MemoryRegionType::Usable && Frame::containing_addr(addr as usize) >= self.next_free_frame
+                region.region_type == MemoryRegionType::Usable && Frame::containing_addr(PhysAddr::new(addr as usize)) >= self.next_free_frame
             })
             .min_by_key(|region| region.range.start_addr())
             .map(|x| *x);
 
         self.current_region.map(|region| {
-            let start_frame = Frame::containing_addr(region.range.start_addr() as usize);
+            let start_frame = Frame::containing_addr(PhysAddr::new(region.range.start_addr() as usize));
             if self.next_free_frame < start_frame {
                 self.next_free_frame = start_frame;
             }
         });
     }
 
-    pub fn next_free(self) -> Frame { self.next_free_frame }
+    pub fn next_free(&self) -> Frame { self.next_free_frame.clone() }
+
+    /// Frames below `next_free` that were released and not handed out again.
+    pub fn released(&self) -> impl Iterator<Item=&Frame> {
+        self.free_frames.iter().filter_map(|f| f.as_ref())
+    }
 }
 
 impl <T: FrameSetMut> FrameAllocator for AreaFrameAllocator<T> {
     fn alloc(&mut self) -> Option<Frame> {
+        if let Some(frame) = self.free_frames.iter_mut().find(|f| f.is_some()).and_then(|f| f.take()) {
+            self.frame_set.add(frame.clone()).unwrap_or_else(|_| panic!("allocator's frame set was full"));
+            return Some(frame);
+        }
+
         self.current_region.and_then(|area| {
             let frame = Frame::new(self.next_free_frame.index());
 
             let current_area_last_frame = {
                 let addr = area.range.end_addr() - 1;
-                Frame::containing_addr(addr as usize)
+                Frame::containing_addr(PhysAddr::new(addr as usize))
             };
 
             if frame > current_area_last_frame {
@@ -76,6 +98,15 @@ impl <T: FrameSetMut> FrameAllocator for AreaFrameAllocator<T> {
     }
 
     fn release(&mut self, f: Frame) {
-        self.frame_set.remove(f.index()).unwrap_or_else(|_| panic!("unable to release frame"));
+        let handed_out = f < self.next_free_frame && !self.released().any(|r| *r == f);
+        assert!(handed_out, "double free of frame {}", f.index());
+
+        self.frame_set.remove(f.index()).unwrap_or_else(|_| panic!("double free of frame {}", f.index()));
+
+        let slot = self.free_frames.iter_mut()
+            .find(|f| f.is_none())
+            .unwrap_or_else(|| panic!("bootstrap free list full ({} frames)", MAX_FREE));
+
+        *slot = Some(f);
     }
 }
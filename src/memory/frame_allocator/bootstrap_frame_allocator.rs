@@ -14,7 +14,7 @@ impl BootstrapFrameAllocator {
 
 impl FrameAllocator for BootstrapFrameAllocator {
     fn alloc(&mut self) -> Option<Frame> {
-        let ret = Some(Frame::containing_addr(self.next_free_frame << 12));
+        let ret = Some(Frame::new(self.next_free_frame));
         self.next_free_frame += 1;
 
         ret
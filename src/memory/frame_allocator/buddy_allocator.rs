@@ -0,0 +1,289 @@
+use core::cmp;
+
+use memory::{Frame, FrameAllocator};
+use memory::bitmap::Bitmap;
+use memory::frame_set::FrameSetMut;
+
+/// Largest block the allocator manages: 2^18 frames, i.e. one 1 GiB page.
+pub const MAX_ORDER: usize = 18;
+
+const ORDERS: usize = MAX_ORDER + 1;
+
+/// A binary buddy allocator over physical frames. Hands out naturally aligned blocks of 2^n
+/// frames and merges buddies back together on release.
+///
+/// Free blocks are tracked with one bitmap per order, all packed into a single `Bitmap` whose
+/// storage is carved out during `memory::init`, so the allocator never touches the heap.
+/// Allocated frames are recorded in `frame_set`.
+pub struct BuddyAllocator<T> {
+    free: Bitmap,
+    frame_count: usize,
+    order_offset: [usize; ORDERS],
+    free_blocks: [usize; ORDERS],
+    hint: [usize; ORDERS],
+    frame_set: T,
+}
+
+impl <T: FrameSetMut> BuddyAllocator<T> {
+    /// Bytes of backing storage required for an allocator covering `frame_count` frames.
+    pub fn storage_size(frame_count: usize) -> usize {
+        Bitmap::storage_size(Self::total_bits(frame_count))
+    }
+
+    /// Build an allocator over `frame_count` frames with no free memory. Seed it with
+    /// `add_range`.
+    ///
+    /// `storage` must be mapped, writable, at least `storage_size(frame_count)` bytes long, and
+    /// must never be handed out again.
+    pub unsafe fn new(storage: *mut u64, frame_count: usize, frame_set: T) -> Self {
+        let mut order_offset = [0; ORDERS];
+        let mut offset = 0;
+
+        for order in 0..ORDERS {
+            order_offset[order] = offset;
+            offset += Self::blocks_at(frame_count, order);
+        }
+
+        BuddyAllocator {
+            free: Bitmap::new(storage, offset),
+            frame_count,
+            order_offset,
+            free_blocks: [0; ORDERS],
+            hint: [0; ORDERS],
+            frame_set,
+        }
+    }
+
+    fn blocks_at(frame_count: usize, order: usize) -> usize {
+        (frame_count + (1 << order) - 1) >> order
+    }
+
+    fn total_bits(frame_count: usize) -> usize {
+        (0..ORDERS).map(|order| Self::blocks_at(frame_count, order)).sum()
+    }
+
+    /// Hand the frames `start..=end` to the allocator as free memory.
+    pub fn add_range(&mut self, start: Frame, end: Frame) {
+        assert!(end.index() < self.frame_count, "frame {} out of range", end.index());
+
+        let mut index = start.index();
+
+        while index <= end.index() {
+            let remaining = end.index() - index + 1;
+
+            let order = (0..ORDERS)
+                .take_while(|&order| index % (1 << order) == 0 && (1 << order) <= remaining)
+                .last()
+                .unwrap();
+
+            self.free_block(index, order);
+            index += 1 << order;
+        }
+    }
+
+    /// Allocate a naturally aligned block of 2^`order` frames, returning its first frame.
+    pub fn alloc_order(&mut self, order: usize) -> Option<Frame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let found = (order..ORDERS).find(|&o| self.free_blocks[o] > 0);
+
+        found.map(|mut current| {
+            let index = self.take_block(current);
+
+            while current > order {
+                current -= 1;
+                self.set_free(index + (1 << current), current);
+            }
+
+            for i in index..index + (1 << order) {
+                self.frame_set.add(Frame::new(i)).unwrap_or_else(|_| panic!("allocator's frame set rejected frame {}", i));
+            }
+
+            Frame::new(index)
+        })
+    }
+
+    /// Return a block previously handed out by `alloc_order` with the same `order`.
+    pub fn release_order(&mut self, frame: Frame, order: usize) {
+        let index = frame.index();
+
+        assert!(order <= MAX_ORDER, "invalid order: {}", order);
+        assert_eq!(index % (1 << order), 0, "frame {} is not aligned to order {}", index, order);
+
+        for i in index..index + (1 << order) {
+            self.frame_set.remove(i).unwrap_or_else(|_| panic!("double free of frame {}", i));
+        }
+
+        self.free_block(index, order);
+    }
+
+    /// Number of free frames across all orders.
+    pub fn free_frames(&self) -> usize {
+        self.free_blocks.iter()
+            .enumerate()
+            .map(|(order, count)| count << order)
+            .sum()
+    }
+
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+
+            if buddy >= self.frame_count || !self.is_free(buddy, order) {
+                break;
+            }
+
+            self.clear_free(buddy, order);
+            index = cmp::min(index, buddy);
+            order += 1;
+        }
+
+        self.set_free(index, order);
+    }
+
+    fn take_block(&mut self, order: usize) -> usize {
+        let start = self.order_offset[order];
+        let bit = self.free.first_set_from(start + self.hint[order])
+            .expect("buddy allocator free count out of sync");
+
+        let index = (bit - start) << order;
+        self.clear_free(index, order);
+        index
+    }
+
+    #[inline]
+    fn bit(&self, index: usize, order: usize) -> usize {
+        self.order_offset[order] + (index >> order)
+    }
+
+    #[inline]
+    fn is_free(&self, index: usize, order: usize) -> bool {
+        self.free.get(self.bit(index, order))
+    }
+
+    fn set_free(&mut self, index: usize, order: usize) {
+        let bit = self.bit(index, order);
+        self.free.set(bit);
+        self.free_blocks[order] += 1;
+        self.hint[order] = cmp::min(self.hint[order], index >> order);
+    }
+
+    fn clear_free(&mut self, index: usize, order: usize) {
+        let bit = self.bit(index, order);
+        self.free.clear(bit);
+        self.free_blocks[order] -= 1;
+
+        if self.hint[order] == index >> order {
+            self.hint[order] += 1;
+        }
+    }
+}
+
+impl <T: FrameSetMut> FrameAllocator for BuddyAllocator<T> {
+    fn alloc(&mut self) -> Option<Frame> {
+        self.alloc_order(0)
+    }
+
+    fn release(&mut self, frame: Frame) {
+        self.release_order(frame, 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::frame_set::{BitmapFrameSet, FrameSet};
+
+    fn storage(bytes: usize) -> *mut u64 {
+        Box::leak(vec![0u64; (bytes + 7) / 8].into_boxed_slice()).as_mut_ptr()
+    }
+
+    /// An allocator over `frame_count` frames with `start..=end` free.
+    fn allocator(frame_count: usize, start: usize, end: usize) -> BuddyAllocator<BitmapFrameSet> {
+        let frame_set = unsafe { BitmapFrameSet::new(storage(BitmapFrameSet::storage_size(frame_count)), frame_count) };
+        let storage = storage(BuddyAllocator::<BitmapFrameSet>::storage_size(frame_count));
+
+        let mut allocator = unsafe { BuddyAllocator::new(storage, frame_count, frame_set) };
+        allocator.add_range(Frame::new(start), Frame::new(end));
+        allocator
+    }
+
+    #[test]
+    fn add_range_splits_into_aligned_blocks() {
+        let allocator = allocator(64, 3, 40);
+
+        assert_eq!(allocator.free_frames(), 38);
+
+        // 3, 4-7, 8-15, 16-31, 32-39, 40
+        assert_eq!(allocator.free_blocks[0], 2);
+        assert_eq!(allocator.free_blocks[2], 1);
+        assert_eq!(allocator.free_blocks[3], 2);
+        assert_eq!(allocator.free_blocks[4], 1);
+    }
+
+    #[test]
+    fn alloc_is_naturally_aligned() {
+        let mut allocator = allocator(64, 1, 63);
+
+        let block = allocator.alloc_order(3).unwrap();
+        assert_eq!(block.index() % 8, 0);
+        assert_eq!(allocator.free_frames(), 63 - 8);
+    }
+
+    #[test]
+    fn alloc_records_frames_in_set() {
+        let mut allocator = allocator(16, 0, 15);
+
+        let block = allocator.alloc_order(2).unwrap();
+        assert!((block.index()..block.index() + 4).all(|i| allocator.frame_set.contains(&Frame::new(i))));
+    }
+
+    #[test]
+    fn release_merges_buddies() {
+        let mut allocator = allocator(16, 0, 15);
+
+        let frames = (0..16).map(|_| allocator.alloc().unwrap()).collect::<Vec<_>>();
+        assert_eq!(allocator.free_frames(), 0);
+        assert!(allocator.alloc().is_none());
+
+        frames.into_iter().for_each(|f| allocator.release(f));
+
+        assert_eq!(allocator.free_frames(), 16);
+        assert_eq!(allocator.free_blocks[4], 1);
+        assert_eq!(allocator.alloc_order(4).map(|f| f.index()), Some(0));
+    }
+
+    #[test]
+    fn exhausts_and_refuses_large_orders() {
+        let mut allocator = allocator(8, 0, 7);
+
+        assert!(allocator.alloc_order(4).is_none());
+        assert!(allocator.alloc_order(MAX_ORDER + 1).is_none());
+        assert!(allocator.alloc_order(3).is_some());
+        assert!(allocator.alloc().is_none());
+    }
+
+    #[test]
+    fn partial_last_block_never_merges_past_end() {
+        let mut allocator = allocator(12, 0, 11);
+        assert_eq!(allocator.free_frames(), 12);
+
+        let frames = (0..12).map(|_| allocator.alloc().unwrap()).collect::<Vec<_>>();
+        assert!(frames.iter().all(|f| f.index() < 12));
+
+        frames.into_iter().for_each(|f| allocator.release(f));
+        assert_eq!(allocator.free_frames(), 12);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_panics() {
+        let mut allocator = allocator(8, 0, 7);
+
+        let frame = allocator.alloc().unwrap();
+        allocator.release(frame.clone());
+        allocator.release(frame);
+    }
+}
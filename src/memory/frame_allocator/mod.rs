@@ -3,10 +3,12 @@ use super::Frame;
 pub use self::area_frame_allocator::*;
 pub use self::bootstrap_frame_allocator::*;
 pub use self::nop_allocator::*;
+pub use self::buddy_allocator::*;
 
 mod area_frame_allocator;
 mod bootstrap_frame_allocator;
 mod nop_allocator;
+mod buddy_allocator;
 
 pub trait FrameAllocator {
     fn alloc(&mut self) -> Option<Frame>;
@@ -0,0 +1,89 @@
+use bootinfo::{MemoryMap, MemoryRegionType};
+
+use memory::PhysAddr;
+use memory::bitmap::Bitmap;
+use super::{Frame, FrameSet, FrameSetMut};
+
+/// A FrameSet backed by one bit per physical frame. Storage is sized from the bootinfo memory
+/// map and lives in frames carved out of usable memory during `memory::init`, so it does not
+/// depend on the heap. All operations are O(1).
+#[derive(Debug)]
+pub struct BitmapFrameSet {
+    bits: Bitmap,
+}
+
+#[derive(Debug, Clone, Copy, Fail)]
+pub enum BitmapFrameSetError {
+    #[fail(display = "frame out of range: {}", index)]
+    OutOfRange { index: usize },
+
+    #[fail(display = "frame already present: {}", index)]
+    AlreadyPresent { index: usize },
+
+    #[fail(display = "invalid frame: {}", index)]
+    InvalidFrame { index: usize },
+}
+
+impl BitmapFrameSet {
+    /// Number of frames a set needs to cover every usable frame in `memory_map`.
+    pub fn frame_count(memory_map: &MemoryMap) -> usize {
+        memory_map.iter()
+            .filter(|reg| reg.region_type == MemoryRegionType::Usable)
+            .map(|reg| Frame::containing_addr(PhysAddr::new((reg.range.end_addr() - 1) as usize)).index() + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Bytes of backing storage required for a set covering `frame_count` frames.
+    pub fn storage_size(frame_count: usize) -> usize {
+        Bitmap::storage_size(frame_count)
+    }
+
+    /// Build an empty set over `frame_count` frames using `storage` as the bitmap.
+    ///
+    /// `storage` must be mapped, writable, at least `storage_size(frame_count)` bytes long, and
+    /// must never be handed out again.
+    pub unsafe fn new(storage: *mut u64, frame_count: usize) -> Self {
+        BitmapFrameSet {
+            bits: Bitmap::new(storage, frame_count),
+        }
+    }
+}
+
+impl FrameSet for BitmapFrameSet {
+    fn contains(&self, frame: &Frame) -> bool {
+        frame.index() < self.bits.len() && self.bits.get(frame.index())
+    }
+}
+
+impl FrameSetMut for BitmapFrameSet {
+    type Err = BitmapFrameSetError;
+
+    fn add(&mut self, frame: Frame) -> Result<(), BitmapFrameSetError> {
+        let index = frame.index();
+
+        if index >= self.bits.len() {
+            return Err(BitmapFrameSetError::OutOfRange { index });
+        }
+
+        if self.bits.get(index) {
+            return Err(BitmapFrameSetError::AlreadyPresent { index });
+        }
+
+        self.bits.set(index);
+        Ok(())
+    }
+
+    fn remove(&mut self, frame_index: usize) -> Result<Frame, BitmapFrameSetError> {
+        if frame_index >= self.bits.len() {
+            return Err(BitmapFrameSetError::OutOfRange { index: frame_index });
+        }
+
+        if !self.bits.get(frame_index) {
+            return Err(BitmapFrameSetError::InvalidFrame { index: frame_index });
+        }
+
+        self.bits.clear(frame_index);
+        Ok(Frame::new(frame_index))
+    }
+}
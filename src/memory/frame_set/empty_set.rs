@@ -17,6 +17,6 @@ impl FrameSetMut for EmptyFrameSet {
     }
 
     fn remove(&mut self, _: usize) -> Result<Frame, ()> {
-        Ok(Frame::containing_addr(0))
+        Ok(Frame::new(0))
     }
 }
@@ -3,10 +3,12 @@ use super::Frame;
 mod stack_set;
 mod empty_set;
 mod vec_set;
+mod bitmap_set;
 
 pub use self::stack_set::*;
 pub use self::empty_set::*;
 pub use self::vec_set::*;
+pub use self::bitmap_set::*;
 
 pub trait FrameSet {
     fn contains(&self, frame: &Frame) -> bool;
@@ -0,0 +1,240 @@
+use core::mem;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Once;
+
+use super::{Frame, PhysAddr};
+
+static FRAME_TABLE: Once<FrameTable> = Once::new();
+
+/// The kernel-wide frame table, once `memory::init` has built it.
+#[inline]
+pub fn frame_table() -> Option<&'static FrameTable> {
+    FRAME_TABLE.try()
+}
+
+/// Descriptor for `frame`, if the frame table exists and covers it. Frames outside every usable
+/// region (MMIO, firmware) above the highest usable frame have no descriptor.
+#[inline]
+pub fn descriptor(frame: &Frame) -> Option<&'static FrameDescriptor> {
+    frame_table().and_then(|t| t.get(frame))
+}
+
+/// Whether `frame` may be handed back to a frame allocator. Frames outside usable RAM (the
+/// kernel image, the bootloader's tables, firmware) and pinned frames never are. Before the table
+/// exists, the bootstrap allocators keep track of their own frames.
+pub fn allocatable(frame: &Frame) -> bool {
+    match frame_table() {
+        Some(table) => table.get(frame).map_or(false, |d| !d.flags().intersects(RESERVED | PINNED)),
+        None => true,
+    }
+}
+
+bitflags! {
+    pub struct FrameFlags: usize {
+        const RESERVED = 1 << 0;
+        const KERNEL = 1 << 1;
+        const PAGE_TABLE = 1 << 2;
+        const USER = 1 << 3;
+        const PINNED = 1 << 4;
+    }
+}
+
+/// Per-frame metadata. All fields are atomic so descriptors can be updated through a shared
+/// reference from anywhere, including interrupt context.
+#[derive(Debug)]
+pub struct FrameDescriptor {
+    ref_count: AtomicUsize,
+    flags: AtomicUsize,
+    owner: AtomicUsize,
+}
+
+impl FrameDescriptor {
+    #[inline]
+    pub fn ref_count(&self) -> usize {
+        self.ref_count.load(Ordering::Acquire)
+    }
+
+    /// Add a reference, returning the new count.
+    #[inline]
+    pub fn inc_ref(&self) -> usize {
+        self.ref_count.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Count the mapping a frame had before the table existed: a zero count becomes one. Returns
+    /// the count afterwards.
+    pub fn count_existing(&self) -> usize {
+        match self.ref_count.compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => 1,
+            Err(actual) => actual,
+        }
+    }
+
+    /// Drop a reference, returning the new count. Frames mapped before the table existed start at
+    /// zero, so this saturates rather than wrapping.
+    pub fn dec_ref(&self) -> usize {
+        let mut current = self.ref_count.load(Ordering::Acquire);
+
+        loop {
+            if current == 0 {
+                return 0;
+            }
+
+            match self.ref_count.compare_exchange_weak(current, current - 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return current - 1,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    #[inline]
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_truncate(self.flags.load(Ordering::Acquire))
+    }
+
+    #[inline]
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::AcqRel);
+    }
+
+    #[inline]
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::AcqRel);
+    }
+
+    #[inline]
+    pub fn owner(&self) -> usize {
+        self.owner.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub fn set_owner(&self, owner: usize) {
+        self.owner.store(owner, Ordering::Release);
+    }
+}
+
+/// Descriptors for every frame up to the highest usable frame, indexed by `Frame::index()`.
+#[derive(Debug)]
+pub struct FrameTable {
+    descriptors: &'static [FrameDescriptor],
+}
+
+impl FrameTable {
+    /// Bytes of backing storage required for a table covering `frame_count` frames.
+    pub fn storage_size(frame_count: usize) -> usize {
+        frame_count * mem::size_of::<FrameDescriptor>()
+    }
+
+    /// Build the global frame table in `storage` and classify frames from `memory_map`. Anything
+    /// that is not usable is marked `RESERVED`; kernel and page table regions get their own flags.
+    ///
+    /// `storage` must be zeroed, mapped, at least `storage_size(frame_count)` bytes long, and must
+    /// never be handed out again.
+    pub unsafe fn init(storage: *mut FrameDescriptor, frame_count: usize, memory_map: &MemoryMap) -> &'static FrameTable {
+        let table = FRAME_TABLE.call_once(|| FrameTable {
+            descriptors: slice::from_raw_parts(storage, frame_count),
+        });
+
+        table.descriptors.iter().for_each(|d| d.insert_flags(RESERVED));
+
+        memory_map.iter().for_each(|reg| {
+            let flags = match reg.region_type {
+                MemoryRegionType::Usable => None,
+                MemoryRegionType::Kernel => Some(KERNEL),
+                MemoryRegionType::PageTable => Some(PAGE_TABLE),
+                _ => return,
+            };
+
+            let start = Frame::containing_addr(PhysAddr::new(reg.range.start_addr() as usize));
+            let end = Frame::containing_addr(PhysAddr::new((reg.range.end_addr() - 1) as usize));
+
+            Frame::range_inclusive(start, end)
+                .filter_map(|f| table.get(&f))
+                .for_each(|d| match flags {
+                    None => d.remove_flags(RESERVED),
+                    Some(flags) => d.insert_flags(flags),
+                });
+        });
+
+        table
+    }
+
+    #[inline]
+    pub fn get(&self, frame: &Frame) -> Option<&FrameDescriptor> {
+        self.descriptors.get(frame.index())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.descriptors.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn descriptor() -> FrameDescriptor {
+        FrameDescriptor {
+            ref_count: AtomicUsize::new(0),
+            flags: AtomicUsize::new(0),
+            owner: AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn ref_counts() {
+        let d = descriptor();
+
+        assert_eq!(d.inc_ref(), 1);
+        assert_eq!(d.inc_ref(), 2);
+        assert_eq!(d.dec_ref(), 1);
+        assert_eq!(d.dec_ref(), 0);
+        assert_eq!(d.ref_count(), 0);
+    }
+
+    #[test]
+    fn dec_ref_saturates() {
+        let d = descriptor();
+        assert_eq!(d.dec_ref(), 0);
+        assert_eq!(d.ref_count(), 0);
+    }
+
+    #[test]
+    fn count_existing_only_raises_zero() {
+        let d = descriptor();
+        assert_eq!(d.count_existing(), 1);
+        assert_eq!(d.count_existing(), 1);
+
+        d.inc_ref();
+        assert_eq!(d.count_existing(), 2);
+    }
+
+    #[test]
+    fn flags() {
+        let d = descriptor();
+
+        d.insert_flags(RESERVED | KERNEL);
+        assert_eq!(d.flags(), RESERVED | KERNEL);
+
+        d.remove_flags(RESERVED);
+        assert_eq!(d.flags(), KERNEL);
+    }
+
+    #[test]
+    fn get_is_bounded() {
+        let descriptors = Box::leak((0..4).map(|_| descriptor()).collect::<Vec<_>>().into_boxed_slice());
+        let table = FrameTable { descriptors };
+
+        assert_eq!(table.len(), 4);
+        assert!(table.get(&Frame::new(3)).is_some());
+        assert!(table.get(&Frame::new(4)).is_none());
+    }
+
+    #[test]
+    fn storage_size() {
+        assert_eq!(FrameTable::storage_size(10), 10 * mem::size_of::<FrameDescriptor>());
+    }
+}
@@ -0,0 +1,74 @@
+use memory::paging::{EntryFlags, Page, PageIter};
+
+/// Most lazy regions registered at once.
+const MAX_REGIONS: usize = 32;
+
+/// A virtual range whose pages are only backed by (zeroed) frames when first touched.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+    start: Page,
+    end: Page,
+    flags: EntryFlags,
+}
+
+impl LazyRegion {
+    #[inline]
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    #[inline]
+    pub fn pages(&self) -> PageIter {
+        Page::range_inclusive(self.start, self.end)
+    }
+
+    #[inline]
+    fn contains(&self, page: Page) -> bool {
+        page >= self.start && page <= self.end
+    }
+}
+
+/// The set of lazy regions the page fault handler consults. A fixed array, since the handler
+/// also backs the heap.
+pub struct LazyRegions {
+    regions: [Option<LazyRegion>; MAX_REGIONS],
+}
+
+impl LazyRegions {
+    pub fn new() -> LazyRegions {
+        LazyRegions {
+            regions: [None; MAX_REGIONS],
+        }
+    }
+
+    /// Register `pages` as lazily mapped with `flags`. Panics if the registry is full.
+    pub fn insert(&mut self, pages: PageIter, flags: EntryFlags) {
+        assert!(!pages.is_empty(), "empty lazy region");
+        assert!(!self.regions.iter().filter_map(|r| *r).any(|r| r.start <= pages.end() && pages.start() <= r.end),
+            "overlapping lazy regions");
+
+        let slot = self.regions.iter_mut()
+            .find(|r| r.is_none())
+            .expect("too many lazy regions");
+
+        *slot = Some(LazyRegion {
+            start: pages.start(),
+            end: pages.end(),
+            flags,
+        });
+    }
+
+    /// Unregister the region starting at `start`.
+    pub fn remove(&mut self, start: Page) -> Option<LazyRegion> {
+        self.regions.iter_mut()
+            .find(|r| r.map_or(false, |r| r.start == start))
+            .and_then(|r| r.take())
+    }
+
+    /// The region containing `page`, if any.
+    pub fn find(&self, page: Page) -> Option<LazyRegion> {
+        self.regions.iter()
+            .filter_map(|r| *r)
+            .find(|r| r.contains(page))
+    }
+}
@@ -0,0 +1,134 @@
+use core::ptr;
+
+use bootinfo::MemoryRegionType;
+use spin::Mutex;
+
+use memory::{controller, Frame, PAGE_SIZE, MEMORY_MAP};
+use memory::paging::{self, CacheType, MapError, PageIter, PhysAddr, VirtAddr};
+
+/// A device's registers mapped into kernel address space. Unmapped when dropped.
+#[derive(Debug)]
+pub struct MmioRegion {
+    base: VirtAddr,
+    len: usize,
+    pages: PageIter,
+
+    /// First and last frame index, as recorded in `REGIONS`.
+    frames: (usize, usize),
+}
+
+/// Most MMIO regions mapped at once.
+const MAX_REGIONS: usize = 32;
+
+/// The frames of every mapped MMIO region, as `(first, last)` frame indices. The memory map
+/// doesn't always cover device memory, so this is how the rest of the kernel (e.g. `verify`)
+/// tells it apart from stray mappings. A fixed array, since it's consulted with the memory
+/// controller locked.
+static REGIONS: Mutex<[Option<(usize, usize)>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// Whether `frame` belongs to a mapped MMIO region.
+pub fn is_mmio(frame: &Frame) -> bool {
+    REGIONS.lock().iter()
+        .filter_map(|r| *r)
+        .any(|(first, last)| first <= frame.index() && frame.index() <= last)
+}
+
+/// Map `len` bytes of device memory at `phys` with `cache`. `phys` needn't be page aligned.
+///
+/// Takes the memory controller lock, as does dropping the region, so neither may happen while
+/// it's held.
+pub fn map_mmio(phys: PhysAddr, len: usize, cache: CacheType) -> Result<MmioRegion, MapError> {
+    assert!(len > 0, "empty MMIO region");
+
+    let (start, end) = (phys.as_u64(), phys.as_u64() + len as u64);
+
+    // handing out RAM the frame allocator owns would corrupt whatever it's given to next
+    let usable = MEMORY_MAP.iter()
+        .filter(|reg| reg.region_type == MemoryRegionType::Usable)
+        .any(|reg| reg.range.start_addr() < end && start < reg.range.end_addr());
+
+    if usable {
+        return Err(MapError::UsableMemory { addr: phys });
+    }
+
+    let first = Frame::containing_addr(phys);
+    let last = Frame::containing_addr(PhysAddr::new(phys.as_usize() + len - 1));
+    let frames = (first.index(), last.index());
+
+    {
+        let mut regions = REGIONS.lock();
+        let slot = regions.iter_mut().find(|r| r.is_none()).ok_or(MapError::TooManyMmioRegions)?;
+        *slot = Some(frames);
+    }
+
+    let pages = match controller().lock().vmap(Frame::range_inclusive(first, last), paging::WRITABLE | paging::NX, cache) {
+        Ok(pages) => pages,
+        Err(e) => {
+            forget_frames(frames);
+            return Err(e);
+        },
+    };
+
+    Ok(MmioRegion {
+        base: pages.start().start_addr() + phys.as_usize() % PAGE_SIZE,
+        len,
+        pages,
+        frames,
+    })
+}
+
+impl MmioRegion {
+    #[inline]
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.register::<u32>(offset)) }
+    }
+
+    #[inline]
+    pub fn write32(&self, offset: usize, val: u32) {
+        unsafe { ptr::write_volatile(self.register::<u32>(offset), val) }
+    }
+
+    #[inline]
+    pub fn read64(&self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile(self.register::<u64>(offset)) }
+    }
+
+    /// Pointer to the naturally aligned `T` at `offset`. Panics if it's out of bounds.
+    fn register<T>(&self, offset: usize) -> *mut T {
+        use core::mem::size_of;
+
+        assert!(offset % size_of::<T>() == 0, "misaligned MMIO access at offset {:#x}", offset);
+        assert!(offset + size_of::<T>() <= self.len, "MMIO access at offset {:#x} past the end of a {:#x} byte region", offset, self.len);
+
+        (self.base + offset).as_mut_ptr()
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        if let Err(e) = controller().lock().vunmap(self.pages.clone()) {
+            println!("MMIO region at {:#x} unmapped, but its address range is lost: {}", self.base, e);
+        }
+        forget_frames(self.frames);
+    }
+}
+
+/// Drop one record of `frames` from `REGIONS`.
+fn forget_frames(frames: (usize, usize)) {
+    let mut regions = REGIONS.lock();
+    let slot = regions.iter_mut()
+        .find(|r| **r == Some(frames))
+        .expect("MMIO region was never recorded");
+
+    *slot = None;
+}
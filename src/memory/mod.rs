@@ -1,37 +1,68 @@
 pub use self::frame::Frame;
 pub use self::frame_allocator::*;
 pub use self::frame_set::*;
-pub use self::paging::{PhysicalAddr, VirtualAddr};
-pub use self::stack_allocator::Stack;
-
-use self::frame_allocator::AreaFrameAllocator;
-use self::paging::{Page, ActivePageTable};
+pub use self::paging::{PhysAddr, VirtAddr};
+pub use self::stack_allocator::{Stack, StackGuard, overflowed_stack};
+pub use self::frame_table::{FrameTable, FrameDescriptor, FrameFlags};
+pub use self::address_space::AddressSpace;
+pub use self::page_fault::{handle_page_fault, FaultFlags};
+pub use self::mmio::{map_mmio, MmioRegion};
+pub use self::working_set::WorkingSet;
+pub use self::paging::CacheType;
+pub use self::virt_allocator::VirtAllocError;
+
+use core::cmp;
+
+use self::paging::{Page, PageIter, ActivePageTable, TemporaryPage};
+use self::virt_allocator::VirtAllocator;
+use self::lazy_region::LazyRegions;
+use self::working_set::WorkingSetSampler;
+use spin::{Mutex, Once};
 use lateinit::LateInit;
 use bootinfo::{BootInfo, MemoryMap, MemoryRegion, MemoryRegionType};
 use fixedvec::FixedVec;
 
 mod paging;
+mod bitmap;
 mod stack_allocator;
 mod frame;
+mod address_space;
+mod virt_allocator;
+mod page_fault;
+mod lazy_region;
+mod mmio;
+mod working_set;
 pub mod frame_set;
 pub mod frame_allocator;
+pub mod frame_table;
 pub mod bump_allocator;
 
 pub const PAGE_SIZE: usize = 4096;
 pub const VGA_BASE: usize = 0xb8000;
 
-pub static HEAP_START: LateInit<VirtualAddr> = LateInit::new();
+pub static HEAP_START: LateInit<VirtAddr> = LateInit::new();
 pub const HEAP_INIT_SIZE: usize = 1024 * PAGE_SIZE;
 pub const HEAP_SIZE: usize = 100 * HEAP_INIT_SIZE;
 
 const BOOT_INFO_PTR: *const BootInfo = 0xb0071f0000 as *const _;
 
-pub const KERNEL_BASE: VirtualAddr = 0xffff_8000_0000_0000; // higher half
-pub static KERNEL_MAX: LateInit<VirtualAddr> = LateInit::new();
+pub const KERNEL_BASE: VirtAddr = VirtAddr::new_unchecked(0xffff_8000_0000_0000); // higher half
+pub static KERNEL_MAX: LateInit<VirtAddr> = LateInit::new();
 
 pub static MEMORY_MAP: LateInit<MemoryMap> = LateInit::new();
 
-pub fn init() -> MemoryController {
+/// Scratch page for editing inactive page tables: the last page of the kernel's P4 entry, so it
+/// is reachable from every address space.
+const TEMP_PAGE: VirtAddr = VirtAddr::new_unchecked(0xffff_807f_ffff_f000);
+
+static MEMORY_CONTROLLER: Once<Mutex<MemoryController>> = Once::new();
+
+/// The global memory controller. Panics if `init` hasn't run.
+pub fn controller() -> &'static Mutex<MemoryController> {
+    MEMORY_CONTROLLER.try().expect("memory::init has not run")
+}
+
+pub fn init() -> &'static Mutex<MemoryController> {
     use self::frame_allocator::AreaFrameAllocator;
     use super::HEAP_ALLOCATOR;
 
@@ -46,9 +77,15 @@ pub fn init() -> MemoryController {
     });
     println!();
 
+    paging::enable_nxe();
+    paging::pcid::init();
+    paging::pat::init();
+
     let mut active_table = unsafe { ActivePageTable::new() };
+    active_table.recount_entries();
 
     unmap_bootloader(&mut active_table, &mut memory_map);
+    paging::verify(&active_table, &memory_map);
 
     let mut mem: [MemoryRegion; 4] = unsafe { ::core::mem::uninitialized() };
     let mut kernel_regions = FixedVec::<MemoryRegion>::new(&mut mem);
@@ -65,98 +102,153 @@ pub fn init() -> MemoryController {
 
     println!("kernel end identified at {:#x}", kernel_end);
 
-    unsafe { KERNEL_MAX.init(kernel_end + PAGE_SIZE - (kernel_end % PAGE_SIZE)); }
+    unsafe { KERNEL_MAX.init(kernel_end.align_up(PAGE_SIZE)); }
     println!("KERNEL_MAX: {:#x}", *KERNEL_MAX);
 
-    unsafe { HEAP_START.init(*KERNEL_MAX + PAGE_SIZE); }
+    // the rest of the kernel's P4 entry, up to the temporary page, is handed out on demand
+    let mut virt_allocator = VirtAllocator::new(
+        Page::containing_addr(*KERNEL_MAX),
+        Page::containing_addr(TEMP_PAGE - PAGE_SIZE),
+    );
+
+    // reserve the heap's full extent up front so it can grow in place
+    let heap_range = virt_allocator.alloc(HEAP_SIZE / PAGE_SIZE).expect("no address space for the heap");
+    unsafe { HEAP_START.init(heap_range.start().start_addr()); }
+
+    // only the first HEAP_INIT_SIZE bytes are mapped now; the rest is backed on first touch
+    let mut lazy_regions = LazyRegions::new();
+    lazy_regions.insert(heap_range.clone(), paging::WRITABLE | paging::NX);
+
+    let mut working_sets = WorkingSetSampler::new();
+    working_sets.track(heap_range);
 
     let heap_start_page = Page::containing_addr(*HEAP_START);
     let heap_end_page = Page::containing_addr(*HEAP_START + HEAP_INIT_SIZE - 1);
 
     println!("mapping heap in range: {:#x} - {:#x}", *HEAP_START, *HEAP_START + HEAP_INIT_SIZE - 1);
 
-    let last_tmp_frame = {
+    let frame_count = BitmapFrameSet::frame_count(&memory_map);
+
+    let (tmp_alloc, frame_set, buddy_storage, frame_table_storage) = {
         let mut tmp_alloc = AreaFrameAllocator::new(
             memory_map.clone(),
             EmptyFrameSet,
         );
 
-        Page::range_inclusive(heap_start_page, heap_end_page)
-            .for_each(|p| active_table.map(p, paging::WRITABLE, &mut tmp_alloc));
+        active_table.map_range(Page::range_inclusive(heap_start_page, heap_end_page), paging::WRITABLE | paging::NX, CacheType::WriteBack, &mut tmp_alloc)
+            .expect("unable to map heap")
+            .flush();
 
-        tmp_alloc.next_free()
-    };
+        unsafe { HEAP_ALLOCATOR.lock().init(HEAP_START.as_usize(), HEAP_INIT_SIZE); }
 
-    unsafe { HEAP_ALLOCATOR.lock().init(*HEAP_START, HEAP_INIT_SIZE); }
+        paging::remap_kernel(&mut active_table, &mut tmp_alloc);
 
-    // map APIC after heap
-    map_apic(&mut active_table, heap_end_page + 1, &mut memory_map);
+        let bitmap_size = BitmapFrameSet::storage_size(frame_count);
+        let bitmap_addr = map_metadata(&mut active_table, &mut virt_allocator, bitmap_size, &mut tmp_alloc);
+        println!("mapped frame bitmap ({} frames) at {:#x}", frame_count, bitmap_addr);
 
-    let mut frame_allocator = AreaFrameAllocator::new(
-        memory_map.clone(),
-        VecFrameSet::new(),
-    );
-    frame_allocator.set_start_frame(last_tmp_frame);
+        let mut frame_set = unsafe { BitmapFrameSet::new(bitmap_addr.as_mut_ptr(), frame_count) };
 
-    let stack_allocator = {
-        let stack_start = heap_end_page + 1_000_000;
-        let stack_end = stack_start + 100;
-        let stack_alloc_range = Page::range_inclusive(stack_start, stack_end);
+        let buddy_size = BuddyAllocator::<BitmapFrameSet>::storage_size(frame_count);
+        let buddy_storage = map_metadata(&mut active_table, &mut virt_allocator, buddy_size, &mut tmp_alloc);
+        println!("mapped buddy allocator bitmaps at {:#x}", buddy_storage);
 
-        stack_allocator::StackAllocator::new(stack_alloc_range)
-    };
+        let frame_table_size = FrameTable::storage_size(frame_count);
+        let frame_table_storage = map_metadata(&mut active_table, &mut virt_allocator, frame_table_size, &mut tmp_alloc);
+        println!("mapped frame table at {:#x}", frame_table_storage);
 
-    unsafe { MEMORY_MAP.init(memory_map) };
+        let last_tmp_frame = tmp_alloc.next_free();
 
-    MemoryController {
-        active_table,
-        frame_allocator,
-        stack_allocator,
-    }
-}
+        // record everything bootstrapping handed out (heap and metadata tables, the remapped
+        // kernel's tables) as allocated, so the buddy allocator takes it back when it's freed.
+        // frames tmp_alloc got back are handed to the buddy allocator as free below.
+        memory_map.iter()
+            .filter(|reg| reg.region_type == MemoryRegionType::Usable)
+            .for_each(|reg| {
+                let start = Frame::containing_addr(PhysAddr::new(reg.range.start_addr() as usize));
+                if start >= last_tmp_frame {
+                    return;
+                }
 
-fn map_apic(active_table: &mut ActivePageTable, apic_page: Page, memory_map: &mut MemoryMap) {
-    use io::apic::{APIC_PHYS, APIC_VIRT};
-    use self::paging::{WRITABLE, NX, NO_CACHE, PRESENT, WRITE_THROUGH};
+                let end = Frame::containing_addr(PhysAddr::new((reg.range.end_addr() - 1) as usize));
 
-    const APIC_PHYS_U64: u64 = APIC_PHYS as u64;
-
-    println!("mapping APIC to {:#x}", apic_page.start_addr());
-    unsafe { APIC_VIRT.init(apic_page.start_addr()) };
-
-    let create_region = {
-        let apic_region = memory_map.iter()
-            .find(|reg| {
-                reg.range.start_addr() <= APIC_PHYS_U64 &&
-                    reg.range.end_addr() >= APIC_PHYS_U64 + (PAGE_SIZE as u64)
+                Frame::range_inclusive(start, cmp::min(end, Frame::new(last_tmp_frame.index() - 1)))
+                    .filter(|f| !tmp_alloc.released().any(|r| r == f))
+                    .for_each(|f| frame_set.add(f).unwrap_or_else(|e| panic!("unable to record bootstrap frame: {}", e)));
             });
 
-        if let Some(apic_region) = apic_region {
-            assert_eq!(apic_region.region_type, MemoryRegionType::Reserved);
-            false
-        } else {
-            true
-        }
+        (tmp_alloc, frame_set, buddy_storage, frame_table_storage)
     };
 
-    if create_region {
-        use bootinfo::FrameRange;
+    let last_tmp_frame = tmp_alloc.next_free();
 
-        println!("creating physical region for APIC");
+    let frame_table = unsafe { FrameTable::init(frame_table_storage.as_mut_ptr(), frame_count, &memory_map) };
 
-        memory_map.add_region(MemoryRegion {
-            range: FrameRange::new(APIC_PHYS_U64, APIC_PHYS_U64 + (PAGE_SIZE as u64)),
-            region_type: MemoryRegionType::Reserved,
+    let mut frame_allocator = unsafe { BuddyAllocator::new(buddy_storage.as_mut_ptr(), frame_count, frame_set) };
+
+    // everything below last_tmp_frame that tmp_alloc didn't get back was handed out during
+    // bootstrapping and belongs to the kernel
+    memory_map.iter()
+        .filter(|reg| reg.region_type == MemoryRegionType::Usable)
+        .for_each(|reg| {
+            let region_start = Frame::containing_addr(PhysAddr::new(reg.range.start_addr() as usize));
+            let start = cmp::max(region_start.clone(), last_tmp_frame.clone());
+            let end = Frame::containing_addr(PhysAddr::new((reg.range.end_addr() - 1) as usize));
+
+            if region_start < last_tmp_frame {
+                Frame::range_inclusive(region_start, cmp::min(end.clone(), Frame::new(last_tmp_frame.index() - 1)))
+                    .filter(|f| !tmp_alloc.released().any(|r| r == f))
+                    .filter_map(|f| frame_table.get(&f))
+                    .for_each(|d| d.insert_flags(frame_table::KERNEL));
+            }
+
+            if start <= end {
+                frame_allocator.add_range(start, end);
+            }
         });
-    }
 
-    let apic_frame = Frame::containing_addr(APIC_PHYS);
+    tmp_alloc.released().for_each(|f| frame_allocator.add_range(f.clone(), f.clone()));
+
+    println!("{} frames free", frame_allocator.free_frames());
+
+    #[cfg(feature = "physmap")]
+    paging::physmap::init(&mut active_table, &memory_map, &mut frame_allocator);
+
+    let stack_allocator = stack_allocator::StackAllocator::new();
+
+    let temp_page = TemporaryPage::new(Page::containing_addr(TEMP_PAGE), &mut frame_allocator);
+
+    paging::verify(&active_table, &memory_map);
+
+    unsafe { MEMORY_MAP.init(memory_map) };
+
+    MEMORY_CONTROLLER.call_once(|| Mutex::new(MemoryController {
+        active_table,
+        frame_allocator,
+        stack_allocator,
+        virt_allocator,
+        lazy_regions,
+        working_sets,
+        temp_page,
+    }))
+}
+
+/// Map `size` bytes of fresh, zeroed kernel metadata, taking the backing frames from `allocator`.
+/// Returns the address of the first byte.
+fn map_metadata<A>(active_table: &mut ActivePageTable, virt_allocator: &mut VirtAllocator, size: usize, allocator: &mut A) -> VirtAddr
+    where A: FrameAllocator
+{
+    use core::ptr;
+
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let range = virt_allocator.alloc_mapped(pages, paging::WRITABLE | paging::NX, active_table, allocator)
+        .expect("unable to map kernel metadata");
+
+    let (start_page, end_page) = (range.start(), range.end());
 
-    // NOTE: the page needs to be mapped strong uncacheable (UC) according to the intel system programming guide
-    // by default this means we need PAT3 or PAT7 => cache disable + write-through
-    // PLEASE GOD NEVER TOUCH THE PAT MSR
-    // see 4-34, Vol. 3A (paging), 11-35 (programming the PAT)
-    active_table.map_to(apic_page, apic_frame, WRITABLE | NX | NO_CACHE | PRESENT | WRITE_THROUGH, &mut NopAllocator)
+    unsafe { ptr::write_bytes(start_page.start_addr().as_mut_ptr::<u8>(), 0, end_page.start_addr() - start_page.start_addr() + PAGE_SIZE); }
+
+    start_page.start_addr()
 }
 
 fn unmap_bootloader(active_table: &mut ActivePageTable, memory_map: &mut MemoryMap) {
@@ -164,26 +256,35 @@ fn unmap_bootloader(active_table: &mut ActivePageTable, memory_map: &mut MemoryM
     memory_map.iter_mut()
         .filter(|reg| reg.region_type == MemoryRegionType::Bootloader)
         .for_each(|reg| {
-            let start_page = Page::containing_addr(reg.range.start_addr() as usize);
-            let end_page = Page::containing_addr(reg.range.end_addr() as usize - 1);
+            // identity mapped, so the physical range is also the virtual one
+            let start_page = Page::containing_addr(VirtAddr::new(reg.range.start_addr() as usize));
+            let end_page = Page::containing_addr(VirtAddr::new(reg.range.end_addr() as usize - 1));
 
-            Page::range_inclusive(start_page, end_page)
-                .for_each(|p| active_table.unmap(p, &mut NopAllocator));
+            active_table.unmap_range(Page::range_inclusive(start_page, end_page), &mut NopAllocator)
+                .expect("unable to unmap bootloader")
+                .flush();
 
             reg.region_type = MemoryRegionType::Usable;
         });
 }
 
-// extend the heap to its full capacity. should only run after page fault handler is enabled.
+// extend the heap to its full capacity. should only run after page fault handler is enabled, since
+// pages past HEAP_INIT_SIZE are only mapped when they're first touched.
 pub fn extend_heap() {
     use super::HEAP_ALLOCATOR;
     unsafe { HEAP_ALLOCATOR.lock().extend(HEAP_SIZE - HEAP_INIT_SIZE) }
 }
 
+/// Owns the kernel's page tables and allocators. Heap pages past `HEAP_INIT_SIZE` are mapped by
+/// the page fault handler, which needs this lock, so nothing holding it may touch unmapped heap.
 pub struct MemoryController {
     active_table: paging::ActivePageTable,
-    frame_allocator: AreaFrameAllocator<VecFrameSet>, // TODO: replace
+    frame_allocator: BuddyAllocator<BitmapFrameSet>,
     stack_allocator: stack_allocator::StackAllocator,
+    virt_allocator: VirtAllocator,
+    lazy_regions: LazyRegions,
+    working_sets: WorkingSetSampler,
+    temp_page: TemporaryPage,
 }
 
 impl MemoryController {
@@ -191,9 +292,101 @@ impl MemoryController {
         let &mut MemoryController {
             ref mut active_table,
             ref mut frame_allocator,
-            ref mut stack_allocator
+            ref mut stack_allocator,
+            ref mut virt_allocator,
+            ..
+        } = self;
+
+        stack_allocator.alloc(active_table, frame_allocator, virt_allocator, size_in_pages)
+    }
+
+    /// Unmap `stack` and release its frames. Its address range is reused by later allocations.
+    pub fn free_stack(&mut self, stack: Stack) -> Result<(), VirtAllocError> {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut stack_allocator,
+            ref mut virt_allocator,
+            ..
         } = self;
 
-        stack_allocator.alloc(active_table, frame_allocator, size_in_pages)
+        stack_allocator.free(stack, active_table, frame_allocator, virt_allocator)
+    }
+
+    /// Estimate the working set of `pages` from now on. The heap is tracked from boot.
+    pub fn track_working_set(&mut self, pages: PageIter) {
+        self.working_sets.track(pages)
+    }
+
+    /// Stop tracking the region starting at `start`, returning its last estimate.
+    pub fn untrack_working_set(&mut self, start: Page) -> Option<WorkingSet> {
+        self.working_sets.untrack(start)
+    }
+
+    /// The working set estimate for the tracked region starting at `start`.
+    pub fn working_set(&self, start: Page) -> Option<WorkingSet> {
+        self.working_sets.get(start)
+    }
+
+    /// Take a working set sample of every tracked region. Called from the LAPIC timer interrupt;
+    /// samples are skipped while the controller is locked.
+    pub fn sample_working_sets(&mut self) {
+        self.working_sets.sample(&mut self.active_table)
+    }
+
+    /// Check the active page table for inconsistencies. Returns the number of problems found.
+    pub fn verify_page_tables(&self) -> usize {
+        paging::verify(&self.active_table, &MEMORY_MAP)
+    }
+
+    /// Print every mapping in the active page table.
+    pub fn dump_page_tables(&self) {
+        paging::dump(&self.active_table)
+    }
+
+    /// Map `frames` into one contiguous range of kernel address space, cached as `cache`.
+    pub fn vmap<I>(&mut self, frames: I, flags: paging::EntryFlags, cache: CacheType) -> Result<PageIter, paging::MapError>
+        where I: ExactSizeIterator<Item=Frame>
+    {
+        self.virt_allocator.vmap(frames, flags, cache, &mut self.active_table, &mut self.frame_allocator)
+    }
+
+    /// Undo `vmap`. The frames stay with the caller.
+    pub fn vunmap(&mut self, range: PageIter) -> Result<(), VirtAllocError> {
+        self.virt_allocator.vunmap(range, &mut self.active_table, &mut self.frame_allocator)
+    }
+
+    /// Reserve `pages` pages of kernel address space without backing them. Each page is mapped
+    /// to a zeroed frame with `flags` the first time it's touched.
+    pub fn reserve_lazy(&mut self, pages: usize, flags: paging::EntryFlags) -> Result<PageIter, paging::MapError> {
+        let range = self.virt_allocator.alloc(pages).ok_or(paging::MapError::OutOfAddressSpace)?;
+        self.lazy_regions.insert(range.clone(), flags);
+
+        Ok(range)
+    }
+
+    /// Release a range from `reserve_lazy`, along with whatever pages of it were touched.
+    pub fn free_lazy(&mut self, range: PageIter) -> Result<(), VirtAllocError> {
+        let region = self.lazy_regions.remove(range.start()).expect("not a lazy region");
+
+        for page in region.pages() {
+            if self.active_table.translate_page(page).is_some() {
+                self.active_table.unmap(page, &mut self.frame_allocator)
+                    .expect("unable to unmap lazy page")
+                    .flush();
+            }
+        }
+
+        self.virt_allocator.free(region.pages())
+    }
+
+    /// Allocate a naturally aligned, physically contiguous block of 2^`order` frames.
+    pub fn alloc_frames(&mut self, order: usize) -> Option<Frame> {
+        self.frame_allocator.alloc_order(order)
+    }
+
+    /// Release a block obtained from `alloc_frames` with the same `order`.
+    pub fn release_frames(&mut self, frame: Frame, order: usize) {
+        self.frame_allocator.release_order(frame, order)
     }
 }
\ No newline at end of file
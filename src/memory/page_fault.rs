@@ -0,0 +1,160 @@
+use core::ptr;
+
+use super::{MEMORY_CONTROLLER, MemoryController, PAGE_SIZE, VirtAddr};
+use super::{frame_table, FrameAllocator};
+use super::paging::{self, physmap, CacheType, EntryFlags, Page};
+
+bitflags! {
+    /// The error code the CPU pushes for a page fault.
+    pub struct FaultFlags: u64 {
+        /// The page was present; the fault is a protection violation.
+        const PRESENT = 1 << 0;
+        const WRITE = 1 << 1;
+        const USER = 1 << 2;
+        const RESERVED_BIT = 1 << 3;
+        const INSTRUCTION_FETCH = 1 << 4;
+    }
+}
+
+/// Try to resolve a page fault at `addr`. Returns false if the fault is fatal.
+///
+/// Runs in interrupt context, so it only ever `try_lock`s the memory controller: a fault taken
+/// while the controller is held can't be resolved.
+pub fn handle_page_fault(addr: VirtAddr, flags: FaultFlags) -> bool {
+    let mut controller = match MEMORY_CONTROLLER.try().and_then(|c| c.try_lock()) {
+        Some(controller) => controller,
+        None => {
+            println!("page fault at {:#x} with the memory controller unavailable", addr);
+            return false;
+        },
+    };
+
+    let handled = controller.handle_page_fault(addr, flags);
+
+    if !handled {
+        paging::dump_path(&controller.active_table, addr);
+    }
+
+    handled
+}
+
+impl MemoryController {
+    /// Dispatch a page fault to whatever owns the faulting address.
+    pub fn handle_page_fault(&mut self, addr: VirtAddr, flags: FaultFlags) -> bool {
+        let page = Page::containing_addr(addr);
+
+        if flags.contains(PRESENT | WRITE) {
+            return self.resolve_cow(page);
+        }
+
+        if !flags.contains(PRESENT) {
+            if let Some(region) = self.lazy_regions.find(page) {
+                if flags.contains(USER) && !region.flags().contains(paging::USER_ACCESSIBLE) {
+                    return false;
+                }
+
+                return self.map_zeroed(page, region.flags());
+            }
+        }
+
+        false
+    }
+
+    /// Handle a write to a copy-on-write page: copy the frame if it's still shared, otherwise
+    /// just make the mapping writable again. False if `page` isn't COW.
+    fn resolve_cow(&mut self, page: Page) -> bool {
+        let (frame, flags) = match self.active_table.page_entry(page) {
+            Some((frame, flags)) => (frame, flags),
+            None => return false,
+        };
+
+        if !flags.contains(paging::COW) {
+            return false;
+        }
+
+        let writable = (flags & !(paging::COW | paging::ACCESSED | paging::DIRTY)) | paging::WRITABLE;
+        let shared = frame_table::descriptor(&frame).map_or(true, |d| d.ref_count() > 1);
+
+        if !shared {
+            return match self.active_table.update_flags(page, writable) {
+                Ok((_, flush)) => {
+                    flush.flush();
+                    true
+                },
+                Err(_) => false,
+            };
+        }
+
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temp_page,
+            ..
+        } = self;
+
+        let copy = match frame_allocator.alloc() {
+            Some(copy) => copy,
+            None => {
+                println!("out of frames copying COW page {:#x}", page.start_addr());
+                return false;
+            },
+        };
+
+        unsafe {
+            if physmap::enabled() {
+                ptr::copy_nonoverlapping(page.start_addr().as_ptr::<u8>(), physmap::phys_to_virt(copy.start_addr()).as_mut_ptr::<u8>(), PAGE_SIZE);
+            } else {
+                let dst = temp_page.map(copy.clone(), active_table);
+                ptr::copy_nonoverlapping(page.start_addr().as_ptr::<u8>(), dst.as_mut_ptr::<u8>(), PAGE_SIZE);
+                temp_page.unmap(active_table);
+            }
+        }
+
+        match active_table.replace_frame(page, copy, writable, frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Back `page` with a fresh zeroed frame. The frame is zeroed before it's mapped, so this
+    /// works for read-only mappings too.
+    fn map_zeroed(&mut self, page: Page, flags: EntryFlags) -> bool {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut temp_page,
+            ..
+        } = self;
+
+        let frame = match frame_allocator.alloc() {
+            Some(frame) => frame,
+            None => {
+                println!("out of frames backing page {:#x}", page.start_addr());
+                return false;
+            },
+        };
+
+        if physmap::enabled() {
+            physmap::zero_frame(&frame);
+        } else {
+            let addr = temp_page.map(frame.clone(), active_table);
+            unsafe { ptr::write_bytes(addr.as_mut_ptr::<u8>(), 0, PAGE_SIZE); }
+            temp_page.unmap(active_table);
+        }
+
+        match active_table.map_to(page, frame.clone(), flags, CacheType::WriteBack, frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                true
+            },
+            Err(e) => {
+                println!("unable to map page {:#x}: {}", page.start_addr(), e);
+                frame_allocator.release(frame);
+                false
+            },
+        }
+    }
+}
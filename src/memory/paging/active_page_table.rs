@@ -1,11 +1,15 @@
 use core::ops::{Deref, DerefMut};
 
 use memory::frame::Frame;
+use memory::FrameAllocator;
 
 use super::mapper::Mapper;
 use super::inactive_page_table::InactivePageTable;
 use super::temporary_page::TemporaryPage;
 use super::entry::*;
+use super::{physmap, MapError, Page, PhysAddr};
+use super::pcid::{self, Pcid};
+use super::pat::CacheType;
 
 pub struct ActivePageTable {
     mapper: Mapper,
@@ -41,8 +45,17 @@ impl ActivePageTable {
         use x86_64::registers::control_regs;
         use x86_64::instructions::tlb;
 
+        // with the physmap, the inactive table can be edited in place
+        if physmap::enabled() {
+            let p4 = physmap::phys_to_virt(table.p4_frame().start_addr());
+            let mut mapper = unsafe { Mapper::from_p4(p4.as_mut_ptr()) };
+
+            f(&mut mapper);
+            return;
+        }
+
         {
-            let backup = Frame::containing_addr(control_regs::cr3().0 as usize);
+            let backup = Frame::containing_addr(PhysAddr::new(control_regs::cr3().0 as usize));
 
             let p4_table = temp_page.map_table_frame(backup.clone(), self);
 
@@ -58,18 +71,56 @@ impl ActivePageTable {
         temp_page.unmap(self);
     }
 
+    /// Share `page` with `dst_table` at the same address, copy-on-write: both mappings end up
+    /// read-only and writes to either take a fault that copies the frame.
+    pub fn share_cow<A>(&mut self,
+                        page: Page,
+                        dst_table: &mut InactivePageTable,
+                        temp_page: &mut TemporaryPage,
+                        allocator: &mut A) -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let (frame, flags, flush) = self.make_cow(page)?;
+        flush.flush();
+
+        let mut result = Ok(());
+
+        self.with(dst_table, temp_page, |mapper| {
+            let (flags, cache) = CacheType::split(flags);
+            result = mapper.map_to(page, frame, flags, cache, allocator).map(|flush| flush.ignore());
+        });
+
+        result
+    }
+
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
         use x86_64::PhysicalAddress;
         use x86_64::registers::control_regs;
 
-        let old_table = InactivePageTable::new_from_p4_frame(Frame::containing_addr(control_regs::cr3().0 as usize));
+        let old_table = InactivePageTable::new_from_p4_frame(Frame::containing_addr(PhysAddr::new(control_regs::cr3().0 as usize)));
 
         unsafe {
             control_regs::cr3_write(PhysicalAddress(
-                new_table.p4_frame().start_addr() as u64
+                new_table.p4_frame().start_addr().as_u64()
             ))
         }
 
         old_table
     }
+
+    /// Like `switch`, but tags the new table with `pcid` so its TLB entries survive switching
+    /// away and back. Same as `switch` if PCIDs are disabled.
+    pub fn switch_pcid(&mut self, new_table: InactivePageTable, pcid: Pcid) -> InactivePageTable {
+        use x86_64::registers::control_regs;
+
+        if !pcid::enabled() {
+            return self.switch(new_table);
+        }
+
+        let old_table = InactivePageTable::new_from_p4_frame(Frame::containing_addr(PhysAddr::new(control_regs::cr3().0 as usize)));
+
+        unsafe { pcid::switch(new_table.p4_frame(), pcid) };
+
+        old_table
+    }
 }
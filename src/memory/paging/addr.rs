@@ -0,0 +1,188 @@
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
+use memory::bump_allocator::{align_down, align_up};
+
+/// Highest physical address x86_64 can express (52 bits).
+const PHYS_ADDR_MAX: usize = (1 << 52) - 1;
+
+/// A physical address. Always fits in 52 bits.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PhysAddr(usize);
+
+/// A canonical virtual address, i.e. bits 48-63 are copies of bit 47.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VirtAddr(usize);
+
+impl PhysAddr {
+    /// Panics if `addr` doesn't fit in 52 bits.
+    pub fn new(addr: usize) -> PhysAddr {
+        Self::try_new(addr).unwrap_or_else(|| panic!("invalid physical address: {:#x}", addr))
+    }
+
+    pub fn try_new(addr: usize) -> Option<PhysAddr> {
+        if addr <= PHYS_ADDR_MAX {
+            Some(PhysAddr(addr))
+        } else {
+            None
+        }
+    }
+
+    /// For constants. The caller guarantees `addr` fits in 52 bits.
+    pub const fn new_unchecked(addr: usize) -> PhysAddr {
+        PhysAddr(addr)
+    }
+
+    #[inline]
+    pub fn as_usize(self) -> usize {
+        self.0
+    }
+
+    #[inline]
+    pub fn as_u64(self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn align_down(self, align: usize) -> PhysAddr {
+        PhysAddr(align_down(self.0, align))
+    }
+
+    pub fn align_up(self, align: usize) -> PhysAddr {
+        PhysAddr::new(align_up(self.0, align))
+    }
+
+    pub fn is_aligned(self, align: usize) -> bool {
+        self.align_down(align) == self
+    }
+
+    pub fn checked_add(self, rhs: usize) -> Option<PhysAddr> {
+        self.0.checked_add(rhs).and_then(PhysAddr::try_new)
+    }
+
+    pub fn checked_sub(self, rhs: usize) -> Option<PhysAddr> {
+        self.0.checked_sub(rhs).map(PhysAddr)
+    }
+}
+
+impl VirtAddr {
+    /// Panics if `addr` is not canonical.
+    pub fn new(addr: usize) -> VirtAddr {
+        Self::try_new(addr).unwrap_or_else(|| panic!("invalid addr: {:#x}", addr))
+    }
+
+    pub fn try_new(addr: usize) -> Option<VirtAddr> {
+        if addr < 0x0000_8000_0000_0000 || addr >= 0xffff_8000_0000_0000 {
+            Some(VirtAddr(addr))
+        } else {
+            None
+        }
+    }
+
+    /// For constants. The caller guarantees `addr` is canonical.
+    pub const fn new_unchecked(addr: usize) -> VirtAddr {
+        VirtAddr(addr)
+    }
+
+    #[inline]
+    pub fn as_usize(self) -> usize {
+        self.0
+    }
+
+    #[inline]
+    pub fn as_u64(self) -> u64 {
+        self.0 as u64
+    }
+
+    #[inline]
+    pub fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    #[inline]
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    pub fn align_down(self, align: usize) -> VirtAddr {
+        VirtAddr::new(align_down(self.0, align))
+    }
+
+    pub fn align_up(self, align: usize) -> VirtAddr {
+        VirtAddr::new(align_up(self.0, align))
+    }
+
+    pub fn is_aligned(self, align: usize) -> bool {
+        align_down(self.0, align) == self.0
+    }
+
+    /// `None` on overflow or if the result is not canonical.
+    pub fn checked_add(self, rhs: usize) -> Option<VirtAddr> {
+        self.0.checked_add(rhs).and_then(VirtAddr::try_new)
+    }
+
+    /// `None` on underflow or if the result is not canonical.
+    pub fn checked_sub(self, rhs: usize) -> Option<VirtAddr> {
+        self.0.checked_sub(rhs).and_then(VirtAddr::try_new)
+    }
+}
+
+impl From<PhysAddr> for u64 {
+    fn from(addr: PhysAddr) -> u64 {
+        addr.as_u64()
+    }
+}
+
+macro_rules! addr_impls {
+    ($ty:ident) => {
+        impl Add<usize> for $ty {
+            type Output = $ty;
+
+            fn add(self, rhs: usize) -> $ty {
+                self.checked_add(rhs).unwrap_or_else(|| panic!("{:#x} + {:#x} out of range", self.0, rhs))
+            }
+        }
+
+        impl AddAssign<usize> for $ty {
+            fn add_assign(&mut self, rhs: usize) {
+                *self = *self + rhs;
+            }
+        }
+
+        impl Sub<usize> for $ty {
+            type Output = $ty;
+
+            fn sub(self, rhs: usize) -> $ty {
+                self.checked_sub(rhs).unwrap_or_else(|| panic!("{:#x} - {:#x} out of range", self.0, rhs))
+            }
+        }
+
+        impl SubAssign<usize> for $ty {
+            fn sub_assign(&mut self, rhs: usize) {
+                *self = *self - rhs;
+            }
+        }
+
+        impl Sub<$ty> for $ty {
+            type Output = usize;
+
+            fn sub(self, rhs: $ty) -> usize {
+                self.0.checked_sub(rhs.0).expect("address subtraction underflowed")
+            }
+        }
+
+        impl fmt::Debug for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, concat!(stringify!($ty), "({:#x})"), self.0)
+            }
+        }
+
+        impl fmt::LowerHex for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::LowerHex::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
+addr_impls!(PhysAddr);
+addr_impls!(VirtAddr);
@@ -0,0 +1,184 @@
+use core::fmt;
+
+use memory::PAGE_SIZE;
+use super::{CacheType, Mapper, Page, VirtAddr, ENTRY_COUNT};
+use super::entry::*;
+
+const P2_SPAN: usize = ENTRY_COUNT * PAGE_SIZE;
+const P3_SPAN: usize = ENTRY_COUNT * P2_SPAN;
+
+/// Print every mapping in `mapper`, merging virtually and physically contiguous pages with the
+/// same flags into one line:
+///
+///     0xffff800000000000-0xffff8000001fffff -> 0x100000 RX GLOBAL
+///
+/// The recursive entry is skipped.
+pub fn dump(mapper: &Mapper) {
+    let mut runs = Runs { run: None };
+    let p4 = mapper.p4();
+
+    for i4 in 0..ENTRY_COUNT - 1 {
+        let p3 = match p4.next_table(i4) {
+            Some(p3) => p3,
+            None => continue,
+        };
+
+        for (i3, e3) in p3.iter().enumerate() {
+            if e3.flags().contains(PRESENT | HUGE_PAGE) {
+                let frame = e3.pointed_huge_frame().unwrap();
+                runs.add(addr(i4, i3, 0, 0), frame.start_addr().as_usize(), P3_SPAN, e3.leaf_flags(true));
+                continue;
+            }
+
+            let p2 = match p3.next_table(i3) {
+                Some(p2) => p2,
+                None => continue,
+            };
+
+            for (i2, e2) in p2.iter().enumerate() {
+                if e2.flags().contains(PRESENT | HUGE_PAGE) {
+                    let frame = e2.pointed_huge_frame().unwrap();
+                    runs.add(addr(i4, i3, i2, 0), frame.start_addr().as_usize(), P2_SPAN, e2.leaf_flags(true));
+                    continue;
+                }
+
+                let p1 = match p2.next_table(i2) {
+                    Some(p1) => p1,
+                    None => continue,
+                };
+
+                for (i1, e1) in p1.iter().enumerate() {
+                    if let Some(frame) = e1.pointed_frame() {
+                        runs.add(addr(i4, i3, i2, i1), frame.start_addr().as_usize(), PAGE_SIZE, e1.leaf_flags(false));
+                    }
+                }
+            }
+        }
+    }
+
+    runs.flush();
+}
+
+/// Print the entries translating `addr` at every level, down to the leaf or the first entry
+/// that isn't present.
+pub fn dump_path(mapper: &Mapper, addr: VirtAddr) {
+    let page = Page::containing_addr(addr);
+
+    println!("page table path for {:#x}:", addr);
+
+    let p4 = mapper.p4();
+    if !print_entry("P4", page.p4_index(), &p4[page.p4_index()], false) {
+        return;
+    }
+
+    let p3 = p4.next_table(page.p4_index()).unwrap();
+    let huge = p3[page.p3_index()].flags().contains(HUGE_PAGE);
+    if !print_entry("P3", page.p3_index(), &p3[page.p3_index()], huge) || huge {
+        return;
+    }
+
+    let p2 = p3.next_table(page.p3_index()).unwrap();
+    let huge = p2[page.p2_index()].flags().contains(HUGE_PAGE);
+    if !print_entry("P2", page.p2_index(), &p2[page.p2_index()], huge) || huge {
+        return;
+    }
+
+    let p1 = p2.next_table(page.p2_index()).unwrap();
+    print_entry("P1", page.p1_index(), &p1[page.p1_index()], false);
+}
+
+/// Print one entry of a `dump_path`. False if it isn't present.
+fn print_entry(level: &str, index: usize, entry: &Entry, huge: bool) -> bool {
+    let frame = if huge { entry.pointed_huge_frame() } else { entry.pointed_frame() };
+
+    match frame {
+        Some(frame) => {
+            println!("  {}[{}]: {:#x} {}{}", level, index, frame.start_addr(), Flags(entry.leaf_flags(huge)),
+                if huge { " HUGE" } else { "" });
+            true
+        },
+        None => {
+            println!("  {}[{}]: not present", level, index);
+            false
+        },
+    }
+}
+
+fn addr(i4: usize, i3: usize, i2: usize, i1: usize) -> usize {
+    Page::from_table_indices(i4, i3, i2, i1).start_addr().as_usize()
+}
+
+/// Virtually and physically contiguous mappings with the same flags. `end` is exclusive.
+struct Run {
+    start: usize,
+    end: usize,
+    phys: usize,
+    flags: EntryFlags,
+}
+
+struct Runs {
+    run: Option<Run>,
+}
+
+impl Runs {
+    fn add(&mut self, virt: usize, phys: usize, size: usize, flags: EntryFlags) {
+        if let Some(ref mut run) = self.run {
+            if run.end == virt && run.phys + (run.end - run.start) == phys && run.flags == flags {
+                run.end += size;
+                return;
+            }
+        }
+
+        self.flush();
+        self.run = Some(Run {
+            start: virt,
+            end: virt + size,
+            phys,
+            flags,
+        });
+    }
+
+    fn flush(&mut self) {
+        if let Some(run) = self.run.take() {
+            println!("{:#018x}-{:#018x} -> {:#x} {}", run.start, run.end - 1, run.phys, Flags(run.flags));
+        }
+    }
+}
+
+/// Flags in the dump's notation: R, W if writable, X if executable, then anything unusual.
+struct Flags(EntryFlags);
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = self.0;
+
+        write!(f, "R")?;
+
+        if flags.contains(WRITABLE) {
+            write!(f, "W")?;
+        }
+
+        if !flags.contains(NX) {
+            write!(f, "X")?;
+        }
+
+        if flags.contains(USER_ACCESSIBLE) {
+            write!(f, " USER")?;
+        }
+
+        if flags.contains(GLOBAL) {
+            write!(f, " GLOBAL")?;
+        }
+
+        if flags.contains(COW) {
+            write!(f, " COW")?;
+        }
+
+        let cache = CacheType::from_flags(flags);
+        if cache != CacheType::WriteBack {
+            write!(f, " {}", cache.name())?;
+        }
+
+        Ok(())
+    }
+}
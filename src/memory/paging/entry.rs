@@ -1,36 +1,90 @@
 use memory::Frame;
+use super::PhysAddr;
+
+/// Bits 52-58 are ignored by the MMU in every kind of entry (59-62 hold the protection key of a
+/// leaf once PKE is on). The first two entries of every table use them to count how many of the
+/// table's entries are in use; see `Table::entry_count`.
+const COUNTER_SHIFT: u64 = 52;
+pub(super) const COUNTER_BITS: usize = 7;
+const COUNTER_MASK: u64 = ((1 << COUNTER_BITS) - 1) << COUNTER_SHIFT;
+
+const ADDRESS_MASK: u64 = 0x000fffff_fffff000;
 
 #[derive(Debug)]
 pub struct Entry(u64);
 
 impl Entry {
     pub fn unused(&self) -> bool {
-        self.0 == 0
+        self.0 & !COUNTER_MASK == 0
     }
 
     pub fn set_unused(&mut self) {
-        self.0 = 0;
+        self.0 &= COUNTER_MASK;
+    }
+
+    /// The entry's bits as the MMU sees them.
+    pub(super) fn raw(&self) -> u64 {
+        self.0
+    }
+
+    pub(super) fn counter(&self) -> usize {
+        ((self.0 & COUNTER_MASK) >> COUNTER_SHIFT) as usize
+    }
+
+    pub(super) fn set_counter(&mut self, count: usize) {
+        assert!(count < 1 << COUNTER_BITS);
+        self.0 = (self.0 & !COUNTER_MASK) | ((count as u64) << COUNTER_SHIFT);
     }
 
+    /// The flags of a table or 4 KiB entry. Never includes `HUGE_PAT`, which would be part of
+    /// the frame address.
     pub fn flags(&self) -> EntryFlags {
-        EntryFlags::from_bits_truncate(self.0)
+        EntryFlags::from_bits_truncate(self.0 & !ADDRESS_MASK)
+    }
+
+    /// The flags of a 2 MiB or 1 GiB entry, including `HUGE_PAT`.
+    pub fn huge_flags(&self) -> EntryFlags {
+        EntryFlags::from_bits_truncate(self.0 & !(ADDRESS_MASK & !HUGE_PAT.bits()))
+    }
+
+    /// The flags of a leaf as they matter for a mapping, in 4 KiB form: no accessed/dirty state,
+    /// and the cache type selected through `PAT` regardless of the entry's size.
+    pub fn leaf_flags(&self, huge: bool) -> EntryFlags {
+        if !huge {
+            return self.flags() & !(ACCESSED | DIRTY);
+        }
+
+        let huge_flags = self.huge_flags();
+        let mut flags = huge_flags & !(HUGE_PAGE | HUGE_PAT | ACCESSED | DIRTY);
+
+        if huge_flags.contains(HUGE_PAT) {
+            flags.insert(PAT);
+        }
+
+        flags
     }
 
     pub fn pointed_frame(&self) -> Option<Frame> {
         self.pointed_addr().map(Frame::containing_addr)
     }
 
+    /// The first frame of a 2 MiB or 1 GiB entry.
+    pub fn pointed_huge_frame(&self) -> Option<Frame> {
+        self.pointed_addr().map(|addr| Frame::containing_addr(PhysAddr::new(addr.as_usize() & !(HUGE_PAT.bits() as usize))))
+    }
+
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
-        assert_eq!(frame.start_addr() & !0x000fffff_fffff000, 0);
-        self.0 = (frame.start_addr() as u64) | flags.bits();
+        assert_eq!(frame.start_addr().as_u64() & !ADDRESS_MASK, 0);
+        assert!(flags.contains(HUGE_PAGE) || !flags.contains(HUGE_PAT), "HUGE_PAT on a 4 KiB entry");
+        self.0 = (self.0 & COUNTER_MASK) | frame.start_addr().as_u64() | flags.bits();
     }
 
-    fn pointed_addr(&self) -> Option<usize> {
+    fn pointed_addr(&self) -> Option<PhysAddr> {
         if !self.flags().contains(PRESENT) {
             return None
         }
 
-        Some(self.0 as usize & 0x000fffff_fffff000)
+        Some(PhysAddr::new((self.0 & ADDRESS_MASK) as usize))
     }
 }
 
@@ -44,7 +98,17 @@ bitflags! {
         const ACCESSED = 1 << 5;
         const DIRTY = 1 << 6;
         const HUGE_PAGE = 1 << 7;
+        /// With `NO_CACHE` and `WRITE_THROUGH`, selects a PAT entry; see `CacheType`. The same
+        /// bit as `HUGE_PAGE`, so only meaningful on 4 KiB entries.
+        const PAT = 1 << 7;
         const GLOBAL = 1 << 8;
+        /// Software-defined: a read-only mapping of a frame that may be shared. Writes fault,
+        /// and the handler copies the frame (or just restores write access if it's no longer
+        /// shared).
+        const COW = 1 << 9;
+        /// `PAT` for 2 MiB and 1 GiB entries. On a 4 KiB entry this bit is part of the frame
+        /// address.
+        const HUGE_PAT = 1 << 12;
         const NX = 1 << 63;
     }
 }
@@ -1,6 +1,7 @@
 use memory::Frame;
 
 use super::*;
+use super::table::{Level4, Table};
 use super::temporary_page::TemporaryPage;
 
 pub struct InactivePageTable {
@@ -9,11 +10,21 @@ pub struct InactivePageTable {
 
 impl InactivePageTable {
     pub fn new(frame: Frame, active_table: &mut ActivePageTable, temp_page: &mut TemporaryPage) -> InactivePageTable {
+        if physmap::enabled() {
+            let table = unsafe { &mut *physmap::phys_to_virt(frame.start_addr()).as_mut_ptr::<Table<Level4>>() };
+            table.zero();
+            table[511].set(frame.clone(), PRESENT | WRITABLE);
+            table.increment_entry_count();
+
+            return InactivePageTable { p4_frame: frame };
+        }
+
         {
             // create a page for the frame, zero it, and recursive-map it
             let table = temp_page.map_table_frame(frame.clone(), active_table);
             table.zero();
             table[511].set(frame.clone(), PRESENT | WRITABLE);
+            table.increment_entry_count();
         }
         temp_page.unmap(active_table);
 
@@ -0,0 +1,195 @@
+use alloc::Vec;
+
+use memory::{Frame, FrameAllocator, KERNEL_BASE, KERNEL_MAX};
+use super::{ActivePageTable, InactivePageTable, Page, Page1G, HugePage, VirtAddr, ENTRY_COUNT};
+use super::entry::*;
+use super::pat::CacheType;
+use super::temporary_page::TemporaryPage;
+
+// section boundaries, defined in linker.ld
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __data_rel_ro_start: u8;
+    static __data_rel_ro_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+}
+
+fn contains(start: &u8, end: &u8, addr: VirtAddr) -> bool {
+    let start = start as *const u8 as usize;
+    let end = end as *const u8 as usize;
+
+    addr.as_usize() >= start && addr.as_usize() < end
+}
+
+/// Permissions for a page of the kernel image, or `None` if `addr` lies outside the image.
+/// Code is RX, read-only data R+NX, writable data RW+NX.
+fn image_flags(addr: VirtAddr) -> Option<EntryFlags> {
+    unsafe {
+        if contains(&__text_start, &__text_end, addr) {
+            Some(PRESENT)
+        } else if contains(&__rodata_start, &__rodata_end, addr) || contains(&__data_rel_ro_start, &__data_rel_ro_end, addr) {
+            Some(PRESENT | NX)
+        } else if contains(&__data_start, &__data_end, addr) || contains(&__bss_start, &__bss_end, addr) {
+            Some(PRESENT | WRITABLE | NX)
+        } else if addr >= KERNEL_BASE && addr < *KERNEL_MAX {
+            // anything the linker put after .bss
+            Some(PRESENT | NX)
+        } else {
+            None
+        }
+    }
+}
+
+/// Every mapping under P4 entry `p4_index` in the active table. 2 MiB pages are split into 4 KiB
+/// ones so they can take per-section permissions; 1 GiB pages are returned separately.
+fn collect_mappings(active_table: &ActivePageTable, p4_index: usize)
+    -> (Vec<(Page, Frame, EntryFlags)>, Vec<(Page1G, Frame, EntryFlags)>)
+{
+    let mut pages = Vec::new();
+    let mut huge_pages = Vec::new();
+
+    let p3 = match active_table.p4().next_table(p4_index) {
+        Some(p3) => p3,
+        None => return (pages, huge_pages),
+    };
+
+    for (i3, e3) in p3.iter().enumerate() {
+        let frame = match e3.pointed_huge_frame() {
+            Some(frame) => frame,
+            None => continue,
+        };
+
+        if e3.flags().contains(HUGE_PAGE) {
+            let page = Page1G::containing_addr(Page::from_table_indices(p4_index, i3, 0, 0).start_addr());
+            huge_pages.push((page, frame, e3.leaf_flags(true)));
+            continue;
+        }
+
+        let p2 = p3.next_table(i3).unwrap();
+
+        for (i2, e2) in p2.iter().enumerate() {
+            let frame = match e2.pointed_huge_frame() {
+                Some(frame) => frame,
+                None => continue,
+            };
+
+            if e2.flags().contains(HUGE_PAGE) {
+                // split into 4 KiB pages, which select the same PAT entry through PAT
+                let flags = e2.leaf_flags(true);
+
+                for i1 in 0..ENTRY_COUNT {
+                    pages.push((Page::from_table_indices(p4_index, i3, i2, i1), Frame::new(frame.index() + i1), flags));
+                }
+
+                continue;
+            }
+
+            let p1 = p2.next_table(i2).unwrap();
+
+            for (i1, e1) in p1.iter().enumerate() {
+                if let Some(frame) = e1.pointed_frame() {
+                    pages.push((Page::from_table_indices(p4_index, i3, i2, i1), frame, e1.leaf_flags(false)));
+                }
+            }
+        }
+    }
+
+    (pages, huge_pages)
+}
+
+/// Build a fresh page table in which the kernel image is mapped with per-section permissions
+/// (W^X) and switch to it. Every other mapping in the kernel's P4 entry (heap, stack, ...) is
+/// copied over with its existing flags, and every other P4 entry is shared with the old table.
+///
+/// The old P4 and the kernel-half tables under it belong to the bootloader and are leaked.
+/// Needs the heap.
+pub fn remap_kernel<A>(active_table: &mut ActivePageTable, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let kernel_p4_index = Page::containing_addr(KERNEL_BASE).p4_index();
+
+    let (pages, huge_pages) = collect_mappings(active_table, kernel_p4_index);
+
+    // skip the recursive entry; the new table has its own
+    let shared = active_table.p4().iter()
+        .enumerate()
+        .take(ENTRY_COUNT - 1)
+        .filter(|&(i, _)| i != kernel_p4_index)
+        .filter_map(|(i, e)| e.pointed_frame().map(|f| (i, f, e.flags())))
+        .collect::<Vec<_>>();
+
+    let mut temp_page = TemporaryPage::new(Page::containing_addr(VirtAddr::new(0xcafe_babe_000)), allocator);
+
+    let mut new_table = {
+        let frame = allocator.alloc().expect("no frames available for new P4");
+        InactivePageTable::new(frame, active_table, &mut temp_page)
+    };
+
+    println!("remapping kernel ({} pages)", pages.len());
+
+    active_table.with(&mut new_table, &mut temp_page, |mapper| {
+        for (i, frame, flags) in shared {
+            mapper.p4_mut()[i].set(frame, flags);
+            mapper.p4_mut().increment_entry_count();
+        }
+
+        for (page, frame, flags) in pages {
+            let (flags, cache) = CacheType::split(image_flags(page.start_addr()).unwrap_or(flags));
+
+            mapper.map_to(page, frame, flags, cache, allocator)
+                .expect("unable to remap kernel page")
+                .ignore();
+        }
+
+        for (page, frame, flags) in huge_pages {
+            assert!(image_flags(page.start_addr()).is_none(), "kernel image is mapped with 1 GiB pages");
+
+            let (flags, cache) = CacheType::split(flags);
+
+            mapper.map_to_huge(page, frame, flags, cache, allocator)
+                .expect("unable to remap kernel huge page")
+                .ignore();
+        }
+    });
+
+    active_table.switch(new_table);
+
+    unsafe {
+        flush_global();
+        enable_write_protect();
+    }
+
+    println!("switched to new page table");
+}
+
+/// Make the NX bit in page table entries valid (EFER.NXE).
+pub fn enable_nxe() {
+    use x86_64::registers::msr::{IA32_EFER, rdmsr, wrmsr};
+
+    unsafe {
+        let efer = rdmsr(IA32_EFER);
+        wrmsr(IA32_EFER, efer | 1 << 11);
+    }
+}
+
+/// Make the kernel respect read-only pages (CR0.WP).
+unsafe fn enable_write_protect() {
+    let cr0: u64;
+    asm!("mov %cr0, $0" : "=r"(cr0) ::: "volatile");
+    asm!("mov $0, %cr0" :: "r"(cr0 | 1 << 16) : "memory" : "volatile");
+}
+
+/// Flush global TLB entries too, by toggling CR4.PGE. The bootloader may have mapped the kernel
+/// global with its old permissions.
+unsafe fn flush_global() {
+    let cr4: u64;
+    asm!("mov %cr4, $0" : "=r"(cr4) ::: "volatile");
+    asm!("mov $0, %cr4" :: "r"(cr4 & !(1 << 7)) : "memory" : "volatile");
+    asm!("mov $0, %cr4" :: "r"(cr4) : "memory" : "volatile");
+}
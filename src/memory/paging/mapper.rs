@@ -1,16 +1,145 @@
+use core::cmp;
 use core::ptr::Unique;
 
 use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use memory::frame_table;
 use memory::frame_set::VecFrameSet;
 
-use super::{ENTRY_COUNT, Page, PhysicalAddr, VirtualAddr};
+use super::{ENTRY_COUNT, Page, PageIter, PhysAddr, VirtAddr};
+use super::page::{HugePage, Page1G, supports_1g_pages};
+use super::pcid::{self, Pcid};
+use super::pat::CacheType;
 use super::entry::*;
-use super::table::{self, Level4, Table};
+use super::table::{self, Level1, Level4, Table, TableLevel};
 
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
 }
 
+#[derive(Debug, Clone, Copy, Fail)]
+pub enum MapError {
+    #[fail(display = "page already mapped: {:#x}", addr)]
+    AlreadyMapped { addr: VirtAddr },
+
+    #[fail(display = "page not mapped: {:#x}", addr)]
+    NotMapped { addr: VirtAddr },
+
+    #[fail(display = "no free frames")]
+    OutOfFrames,
+
+    #[fail(display = "huge page in the way")]
+    HugePageConflict,
+
+    #[fail(display = "frame {:#x} is not aligned to the page size", addr)]
+    MisalignedFrame { addr: PhysAddr },
+
+    #[fail(display = "page size not supported by this cpu")]
+    UnsupportedPageSize,
+
+    #[fail(display = "address belongs to the kernel: {:#x}", addr)]
+    KernelAddress { addr: VirtAddr },
+
+    #[fail(display = "out of virtual address space")]
+    OutOfAddressSpace,
+
+    #[fail(display = "physical range at {:#x} is usable memory", addr)]
+    UsableMemory { addr: PhysAddr },
+
+    #[fail(display = "too many MMIO regions")]
+    TooManyMmioRegions,
+
+    #[fail(display = "frame {:#x} has no descriptor to count its references", addr)]
+    UntrackedFrame { addr: PhysAddr },
+}
+
+/// A pending TLB invalidation for a page whose mapping just changed. The caller decides when to
+/// flush, or can `ignore` it if the page was never reachable through the TLB (e.g. the table is
+/// inactive).
+#[must_use = "page table changes must be flushed or explicitly ignored"]
+pub struct MapperFlush {
+    page: Page,
+    /// A present translation was removed or lost permissions, so other PCIDs may cache a stale
+    /// one. Translations that were only added can't be cached anywhere.
+    downgraded: bool,
+}
+
+impl MapperFlush {
+    /// A flush after a present translation was removed or downgraded.
+    fn new(page: Page) -> MapperFlush {
+        MapperFlush { page, downgraded: true }
+    }
+
+    /// A flush after a not-present page was mapped.
+    fn added(page: Page) -> MapperFlush {
+        MapperFlush { page, downgraded: false }
+    }
+
+    pub fn flush(self) {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        tlb::flush(VirtualAddress(self.page.start_addr().as_usize()));
+
+        if self.downgraded && self.page.p4_index() >= 256 {
+            pcid::kernel_changed();
+        }
+    }
+
+    /// Invalidate the page for an inactive address space tagged `pcid`.
+    pub fn invalidate(self, pcid: Pcid) {
+        pcid::invalidate(pcid, self.page.start_addr());
+
+        if self.downgraded && self.page.p4_index() >= 256 {
+            pcid::kernel_changed();
+        }
+    }
+
+    pub fn ignore(self) {}
+}
+
+/// Above this many pages, flushing a range flushes the whole TLB instead of page by page.
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
+/// A pending TLB invalidation for a run of pages changed by one of the `*_range` methods.
+#[must_use = "page table changes must be flushed or explicitly ignored"]
+pub struct MapperFlushRange {
+    start: Page,
+    count: usize,
+    /// As for `MapperFlush`.
+    downgraded: bool,
+}
+
+impl MapperFlushRange {
+    /// A flush after present translations were removed or downgraded.
+    pub(super) fn new(start: Page, count: usize) -> MapperFlushRange {
+        MapperFlushRange { start, count, downgraded: true }
+    }
+
+    /// A flush after not-present pages were mapped.
+    fn added(start: Page, count: usize) -> MapperFlushRange {
+        MapperFlushRange { start, count, downgraded: false }
+    }
+
+    pub fn flush(self) {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        if self.count > FLUSH_ALL_THRESHOLD {
+            tlb::flush_all();
+        } else {
+            for i in 0..self.count {
+                tlb::flush(VirtualAddress((self.start + i).start_addr().as_usize()));
+            }
+        }
+
+        if self.downgraded && self.count > 0 && (self.start + (self.count - 1)).p4_index() >= 256 {
+            pcid::kernel_changed();
+        }
+    }
+
+    pub fn ignore(self) {}
+}
+
 impl Mapper {
     pub unsafe fn new() -> Mapper {
         Mapper {
@@ -18,6 +147,14 @@ impl Mapper {
         }
     }
 
+    /// A mapper over the P4 at `p4`, which need not be the active one. Only useful when the tables
+    /// below it are reachable too, i.e. through the physmap.
+    pub(super) unsafe fn from_p4(p4: *mut Table<Level4>) -> Mapper {
+        Mapper {
+            p4: Unique::new_unchecked(p4),
+        }
+    }
+
     pub fn p4(&self) -> &Table<Level4> {
         unsafe { self.p4.as_ref() }
     }
@@ -26,59 +163,648 @@ impl Mapper {
         unsafe { self.p4.as_mut() }
     }
 
-    pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+    pub fn map<A>(&mut self, page: Page, flags: EntryFlags, cache: CacheType, allocator: &mut A) -> Result<MapperFlush, MapError>
+        where A: FrameAllocator
+    {
+        let frame = allocator.alloc().ok_or(MapError::OutOfFrames)?;
+
+        match self.map_to(page, frame.clone(), flags, cache, allocator) {
+            Ok(flush) => Ok(flush),
+            Err(e) => {
+                allocator.release(frame);
+                Err(e)
+            },
+        }
+    }
+
+    pub fn identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, cache: CacheType, alloc: &mut A) -> Result<MapperFlush, MapError>
+        where A: FrameAllocator
+    {
+        let page = Page::containing_addr(VirtAddr::new(frame.start_addr().as_usize()));
+        self.map_to(page, frame, flags, cache, alloc)
+    }
+
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Result<MapperFlush, MapError>
+        where A: FrameAllocator
+    {
+        self.unmap_with(page, allocator, true)
+    }
+
+    /// `unmap` for a page whose frame belongs to someone else. Only the frame's reference count
+    /// changes; emptied tables are still freed through `allocator`.
+    pub fn unmap_keep_frame<A>(&mut self, page: Page, allocator: &mut A) -> Result<MapperFlush, MapError>
+        where A: FrameAllocator
+    {
+        self.unmap_with(page, allocator, false)
+    }
+
+    fn unmap_with<A>(&mut self, page: Page, allocator: &mut A, release: bool) -> Result<MapperFlush, MapError>
+        where A: FrameAllocator
+    {
+        if self.translate_page(page).is_none() {
+            return Err(MapError::NotMapped { addr: page.start_addr() });
+        }
+
+        let frame = {
+            let p1 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                .ok_or(MapError::HugePageConflict)?;
+
+            let frame = p1[page.p1_index()].pointed_frame().unwrap();
+            p1[page.p1_index()].set_unused();
+            p1.decrement_entry_count();
+            frame
+        };
+
+        self.free_empty_tables(page, allocator);
+
+        if release {
+            release_frame(frame, allocator);
+        } else {
+            frame_table::descriptor(&frame).map(|d| d.dec_ref());
+        }
+
+        Ok(MapperFlush::new(page))
+    }
+
+    /// Map every page in `pages` to a freshly allocated frame. If any page can't be mapped, the
+    /// pages mapped so far are unmapped again and their frames released.
+    pub fn map_range<A>(&mut self, pages: PageIter, flags: EntryFlags, cache: CacheType, allocator: &mut A) -> Result<MapperFlushRange, MapError>
+        where A: FrameAllocator
+    {
+        let flags = with_cache(flags, cache);
+        self.map_range_with(pages, flags, allocator, true, |allocator| allocator.alloc())
+    }
+
+    /// Map each page in `pages` to the next frame from `frames`, which must yield at least as
+    /// many frames as there are pages. If any page can't be mapped, the pages mapped so far are
+    /// unmapped again; the frames stay with the caller.
+    pub fn map_range_to<A, I>(&mut self, pages: PageIter, frames: I, flags: EntryFlags, cache: CacheType, allocator: &mut A)
+        -> Result<MapperFlushRange, MapError>
+        where A: FrameAllocator, I: IntoIterator<Item=Frame>
+    {
+        let flags = with_cache(flags, cache);
+        let mut frames = frames.into_iter();
+        self.map_range_with(pages, flags, allocator, false, |_| frames.next())
+    }
+
+    /// Unmap every page in `pages`, releasing frames nothing else maps and freeing tables that
+    /// end up empty. If any page in the range isn't mapped by a 4 KiB entry, nothing is changed.
+    pub fn unmap_range<A>(&mut self, pages: PageIter, allocator: &mut A) -> Result<MapperFlushRange, MapError>
+        where A: FrameAllocator
+    {
+        self.unmap_range_with(pages, allocator, true)
+    }
+
+    /// `unmap_range` for pages whose frames belong to someone else, e.g. from `map_range_to`.
+    /// Only the frames' reference counts change; tables are still freed through `allocator`.
+    pub fn unmap_range_keep_frames<A>(&mut self, pages: PageIter, allocator: &mut A) -> Result<MapperFlushRange, MapError>
+        where A: FrameAllocator
+    {
+        self.unmap_range_with(pages, allocator, false)
+    }
+
+    fn unmap_range_with<A>(&mut self, pages: PageIter, allocator: &mut A, release: bool) -> Result<MapperFlushRange, MapError>
+        where A: FrameAllocator
+    {
+        let (start, end) = (pages.start(), pages.end());
+
+        if pages.is_empty() {
+            return Ok(MapperFlushRange::new(start, 0));
+        }
+
+        self.check_mapped(start, end)?;
+        self.unmap_pages(start, end, allocator, release);
+
+        Ok(MapperFlushRange::new(start, (end.start_addr() - start.start_addr()) / PAGE_SIZE + 1))
+    }
+
+    fn map_range_with<A, F>(&mut self, pages: PageIter, flags: EntryFlags, allocator: &mut A, owned: bool, mut next_frame: F)
+        -> Result<MapperFlushRange, MapError>
+        where A: FrameAllocator, F: FnMut(&mut A) -> Option<Frame>
+    {
+        let (start, end) = (pages.start(), pages.end());
+        let mut mapped = 0;
+
+        match self.map_pages(start, end, flags, allocator, &mut next_frame, &mut mapped) {
+            Ok(()) => Ok(MapperFlushRange::added(start, mapped)),
+            Err(e) => {
+                if mapped > 0 {
+                    self.unmap_pages(start, start + (mapped - 1), allocator, owned);
+                }
+
+                Err(e)
+            },
+        }
+    }
+
+    /// Map `start..=end`, walking down to each P1 once and filling it until the run leaves it.
+    /// `mapped` counts the pages mapped so far, so the caller can roll back on failure.
+    fn map_pages<A, F>(&mut self, start: Page, end: Page, flags: EntryFlags, allocator: &mut A, next_frame: &mut F, mapped: &mut usize)
+        -> Result<(), MapError>
+        where A: FrameAllocator, F: FnMut(&mut A) -> Option<Frame>
+    {
+        let mut page = start;
+
+        while page <= end {
+            self.p4_mut()
+                .next_table_create(page.p4_index(), allocator)?
+                .next_table_create(page.p3_index(), allocator)?
+                .next_table_create(page.p2_index(), allocator)?;
+
+            self.upgrade_parents(page, Leaf::P1, flags);
+
+            let p1 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                .unwrap();
+
+            loop {
+                if !p1[page.p1_index()].unused() {
+                    return Err(MapError::AlreadyMapped { addr: page.start_addr() });
+                }
+
+                let frame = next_frame(allocator).ok_or(MapError::OutOfFrames)?;
+
+                frame_table::descriptor(&frame).map(|d| d.inc_ref());
+                p1[page.p1_index()].set(frame, flags | PRESENT);
+                p1.increment_entry_count();
+                *mapped += 1;
+
+                page = page + 1;
+
+                if page > end || page.p1_index() == 0 {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fail unless every page in `start..=end` is mapped by a 4 KiB entry.
+    fn check_mapped(&self, start: Page, end: Page) -> Result<(), MapError> {
+        let mut page = start;
+
+        while page <= end {
+            let run_end = cmp::min(end, page + (ENTRY_COUNT - 1 - page.p1_index()));
+
+            let p1 = match self.p4().next_table(page.p4_index())
+                .and_then(|p3| p3.next_table(page.p3_index()))
+                .and_then(|p2| p2.next_table(page.p2_index())) {
+                Some(p1) => p1,
+                None if self.translate_page(page).is_some() => return Err(MapError::HugePageConflict),
+                None => return Err(MapError::NotMapped { addr: page.start_addr() }),
+            };
+
+            if let Some(p) = Page::range_inclusive(page, run_end).find(|p| p1[p.p1_index()].pointed_frame().is_none()) {
+                return Err(MapError::NotMapped { addr: p.start_addr() });
+            }
+
+            page = run_end + 1;
+        }
+
+        Ok(())
+    }
+
+    /// Clear `start..=end`, which must be fully mapped, one P1 at a time. Frames are only handed
+    /// back to `allocator` if `release` is set; their reference counts drop either way.
+    fn unmap_pages<A>(&mut self, start: Page, end: Page, allocator: &mut A, release: bool)
         where A: FrameAllocator
     {
-        let frame = allocator.alloc().expect("no free frames");
+        let mut page = start;
+
+        while page <= end {
+            let run_end = cmp::min(end, page + (ENTRY_COUNT - 1 - page.p1_index()));
+
+            {
+                let p1 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .expect("unmapping a range that isn't mapped");
+
+                for p in Page::range_inclusive(page, run_end) {
+                    let frame = p1[p.p1_index()].pointed_frame().expect("unmapping a page that isn't mapped");
+                    p1[p.p1_index()].set_unused();
+                    p1.decrement_entry_count();
+
+                    if release {
+                        release_frame(frame, allocator);
+                    } else {
+                        frame_table::descriptor(&frame).map(|d| d.dec_ref());
+                    }
+                }
+            }
 
-        self.map_to(page, frame, flags, allocator);
+            self.free_empty_tables(page, allocator);
+            page = run_end + 1;
+        }
     }
 
-    pub fn identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, alloc: &mut A)
+    pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, cache: CacheType, allocator: &mut A)
+        -> Result<MapperFlush, MapError>
         where A: FrameAllocator
     {
-        let page = Page::containing_addr(frame.start_addr());
-        self.map_to(page, frame, flags, alloc)
+        let flags = with_cache(flags, cache);
+
+        {
+            let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator)?;
+            let p2 = p3.next_table_create(page.p3_index(), allocator)?;
+            let p1 = p2.next_table_create(page.p2_index(), allocator)?;
+
+            if !p1[page.p1_index()].unused() {
+                return Err(MapError::AlreadyMapped { addr: page.start_addr() });
+            }
+
+            frame_table::descriptor(&frame).map(|d| d.inc_ref());
+            p1[page.p1_index()].set(frame, flags | PRESENT);
+            p1.increment_entry_count();
+        }
+
+        self.upgrade_parents(page, Leaf::P1, flags);
+
+        Ok(MapperFlush::added(page))
     }
 
-    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+
+    /// Point the 4 KiB mapping of `page` at `frame` with `flags`, dropping the old frame's
+    /// reference (and releasing it if nothing else maps it).
+    pub fn replace_frame<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A) -> Result<MapperFlush, MapError>
         where A: FrameAllocator
     {
-        assert!(self.translate(page.start_addr()).is_some());
+        let old = {
+            let p1 = self.p1_mut(page)?;
+            let old = p1[page.p1_index()].pointed_frame().ok_or(MapError::NotMapped { addr: page.start_addr() })?;
+
+            frame_table::descriptor(&frame).map(|d| d.inc_ref());
+            p1[page.p1_index()].set(frame, flags | PRESENT);
+            old
+        };
 
-        let p1 = self.p4_mut()
+        release_frame(old, allocator);
+        Ok(MapperFlush::new(page))
+    }
+
+    /// The frame and flags of the 4 KiB mapping of `page`.
+    pub fn page_entry(&self, page: Page) -> Option<(Frame, EntryFlags)> {
+        self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .and_then(|p1| {
+                let entry = &p1[page.p1_index()];
+                entry.pointed_frame().map(|frame| (frame, entry.flags()))
+            })
+    }
+
+    /// Turn the 4 KiB mapping of `page` into a copy-on-write one if it's writable. Returns the
+    /// frame and the flags a second mapping of it should use.
+    pub fn make_cow(&mut self, page: Page) -> Result<(Frame, EntryFlags, MapperFlush), MapError> {
+        let (frame, flags) = match self.page_entry(page) {
+            Some(entry) => entry,
+            None if self.leaf(page).is_some() => return Err(MapError::HugePageConflict),
+            None => return Err(MapError::NotMapped { addr: page.start_addr() }),
+        };
+
+        // the share is counted on top of this mapping, which may predate the frame table
+        frame_table::descriptor(&frame)
+            .ok_or(MapError::UntrackedFrame { addr: frame.start_addr() })?
+            .count_existing();
+
+        let mut shared_flags = flags & !(ACCESSED | DIRTY);
+
+        if flags.intersects(WRITABLE | COW) {
+            shared_flags.remove(WRITABLE);
+            shared_flags.insert(COW);
+        }
+
+        let p1 = self.p1_mut(page)?;
+        p1[page.p1_index()].set(frame.clone(), shared_flags | (flags & (ACCESSED | DIRTY)));
+
+        Ok((frame, shared_flags, MapperFlush::new(page)))
+    }
+
+    pub(super) fn p1_mut(&mut self, page: Page) -> Result<&mut Table<Level1>, MapError> {
+        self.p4_mut()
             .next_table_mut(page.p4_index())
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("no support for huge pages");
+            .ok_or(MapError::NotMapped { addr: page.start_addr() })
+    }
 
-        let frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
+    /// Change the flags of the mapping that covers `page`, keeping its frame. For a huge page
+    /// the whole page changes. Parent entries are loosened as needed so they don't mask the new
+    /// flags (e.g. `USER_ACCESSIBLE` or a cleared `NX`). Returns the previous flags. Flags are in
+    /// 4 KiB form whatever the page size, so `PAT` picks the cache type of a huge page as well.
+    pub fn update_flags(&mut self, page: Page, flags: EntryFlags) -> Result<(EntryFlags, MapperFlush), MapError> {
+        let leaf = self.leaf(page).ok_or(MapError::NotMapped { addr: page.start_addr() })?;
+        let old = self.set_leaf_flags(page, leaf, flags);
 
-        use x86_64::instructions::tlb;
-        use x86_64::VirtualAddress;
+        Ok((old, MapperFlush { page, downgraded: is_downgrade(old, flags | PRESENT) }))
+    }
 
-        tlb::flush(VirtualAddress(page.start_addr()));
+    /// `update_flags` for every page in `pages`. Huge pages in the range are updated once and
+    /// must lie entirely inside it; nothing changes unless every page is mapped. `changed` is
+    /// called with the first page of each mapping that changed and its previous flags. It runs
+    /// with the tables half updated, so it mustn't touch them (or allocate, under the controller
+    /// lock).
+    pub fn update_flags_range<F>(&mut self, pages: PageIter, flags: EntryFlags, mut changed: F)
+        -> Result<MapperFlushRange, MapError>
+        where F: FnMut(Page, EntryFlags)
+    {
+        let (start, end) = (pages.start(), pages.end());
+
+        if pages.is_empty() {
+            return Ok(MapperFlushRange::new(start, 0));
+        }
 
-        // TODO: free page table(s) if empty
+        let mut page = start;
+        while page <= end {
+            let leaf = self.leaf(page).ok_or(MapError::NotMapped { addr: page.start_addr() })?;
+            let (first, last) = leaf.span(page);
 
-        allocator.release(frame);
+            if first < start || last > end {
+                return Err(MapError::HugePageConflict);
+            }
+
+            page = last + 1;
+        }
+
+        let mut downgraded = false;
+        let mut page = start;
+        while page <= end {
+            let leaf = self.leaf(page).unwrap();
+            let old = self.set_leaf_flags(page, leaf, flags);
+            downgraded |= is_downgrade(old, flags | PRESENT);
+            changed(page, old);
+            page = leaf.span(page).1 + 1;
+        }
+
+        Ok(MapperFlushRange {
+            start,
+            count: (end.start_addr() - start.start_addr()) / PAGE_SIZE + 1,
+            downgraded,
+        })
+    }
+
+    /// The level of the entry that maps `page`, if any.
+    fn leaf(&self, page: Page) -> Option<Leaf> {
+        let p3 = self.p4().next_table(page.p4_index())?;
+        let p3_entry = &p3[page.p3_index()];
+
+        if p3_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(Leaf::P3);
+        }
+
+        let p2 = p3.next_table(page.p3_index())?;
+        let p2_entry = &p2[page.p2_index()];
+
+        if p2_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(Leaf::P2);
+        }
+
+        p2.next_table(page.p2_index())
+            .and_then(|p1| p1[page.p1_index()].pointed_frame())
+            .map(|_| Leaf::P1)
+    }
+
+    /// Loosen the entries above a `leaf`-level mapping of `page` so they don't mask `flags`.
+    fn upgrade_parents(&mut self, page: Page, leaf: Leaf, flags: EntryFlags) {
+        let p4 = self.p4_mut();
+        upgrade_parent(&mut p4[page.p4_index()], flags);
+
+        if leaf == Leaf::P3 {
+            return;
+        }
+
+        let p3 = p4.next_table_mut(page.p4_index()).unwrap();
+        upgrade_parent(&mut p3[page.p3_index()], flags);
+
+        if leaf == Leaf::P2 {
+            return;
+        }
+
+        let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+        upgrade_parent(&mut p2[page.p2_index()], flags);
     }
 
-    pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
-        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
-        let p2 = p3.next_table_create(page.p3_index(), allocator);
-        let p1 = p2.next_table_create(page.p2_index(), allocator);
+    fn set_leaf_flags(&mut self, page: Page, leaf: Leaf, flags: EntryFlags) -> EntryFlags {
+        let p4 = self.p4_mut();
+        upgrade_parent(&mut p4[page.p4_index()], flags);
+
+        let p3 = p4.next_table_mut(page.p4_index()).unwrap();
+        if leaf == Leaf::P3 {
+            return set_leaf_entry_flags(&mut p3[page.p3_index()], flags, true);
+        }
+
+        upgrade_parent(&mut p3[page.p3_index()], flags);
+
+        let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+        if leaf == Leaf::P2 {
+            return set_leaf_entry_flags(&mut p2[page.p2_index()], flags, true);
+        }
+
+        upgrade_parent(&mut p2[page.p2_index()], flags);
 
-        assert!(p1[page.p1_index()].unused());
-        p1[page.p1_index()].set(frame, flags | PRESENT);
+        let p1 = p2.next_table_mut(page.p2_index()).unwrap();
+        set_leaf_entry_flags(&mut p1[page.p1_index()], flags, false)
     }
 
+    /// Map a 2 MiB or 1 GiB page to `frame`, which must be aligned to the page size. Intermediate
+    /// tables are allocated from `allocator` as usual.
+    pub fn map_to_huge<P, A>(&mut self, page: P, frame: Frame, flags: EntryFlags, cache: CacheType, allocator: &mut A)
+        -> Result<MapperFlush, MapError>
+        where P: HugePage, A: FrameAllocator
+    {
+        debug_assert!(!flags.intersects(PAT | NO_CACHE | WRITE_THROUGH), "cache bits in mapping flags");
+        let flags = flags | cache.huge_flags();
+
+        let first = page.first_page();
+        let is_1g = P::SIZE == Page1G::SIZE;
+
+        if is_1g && !supports_1g_pages() {
+            return Err(MapError::UnsupportedPageSize);
+        }
+
+        if frame.index() % P::frame_count() != 0 {
+            return Err(MapError::MisalignedFrame { addr: frame.start_addr() });
+        }
+
+        {
+            let p3 = self.p4_mut().next_table_create(first.p4_index(), allocator)?;
+
+            if is_1g {
+                set_huge_entry(p3, first.p3_index(), frame, flags, page.start_addr())?;
+            } else {
+                let p2 = p3.next_table_create(first.p3_index(), allocator)?;
+                set_huge_entry(p2, first.p2_index(), frame, flags, page.start_addr())?;
+            }
+        }
+
+        self.upgrade_parents(first, if is_1g { Leaf::P3 } else { Leaf::P2 }, flags);
+
+        Ok(MapperFlush::added(first))
+    }
+
+    /// Unmap a 2 MiB or 1 GiB page and return its first frame. Since a plain `FrameAllocator`
+    /// can only take back single frames, the caller is responsible for releasing the block.
+    /// Tables emptied by the unmap are freed through `allocator`.
+    pub fn unmap_huge<P, A>(&mut self, page: P, allocator: &mut A) -> Result<(Frame, MapperFlush), MapError>
+        where P: HugePage, A: FrameAllocator
+    {
+        let first = page.first_page();
+        let is_1g = P::SIZE == Page1G::SIZE;
+
+        let frame = {
+            let p3 = self.p4_mut()
+                .next_table_mut(first.p4_index())
+                .ok_or(MapError::NotMapped { addr: page.start_addr() })?;
+
+            if is_1g {
+                clear_huge_entry(p3, first.p3_index(), page.start_addr())?
+            } else {
+                let p2 = p3.next_table_mut(first.p3_index())
+                    .ok_or(MapError::NotMapped { addr: page.start_addr() })?;
+                clear_huge_entry(p2, first.p2_index(), page.start_addr())?
+            }
+        };
+
+        frame_table::descriptor(&frame).map(|d| d.dec_ref());
+        self.free_empty_tables(first, allocator);
+
+        Ok((frame, MapperFlush::new(first)))
+    }
+
+    /// Free the tables on the path to `page` that no longer map anything, bottom-up. The P4 is
+    /// never freed, and neither are kernel-half P3s since every address space shares them.
+    fn free_empty_tables<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let p1_empty = self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .map_or(false, |p1| p1.is_empty());
+
+        if p1_empty {
+            self.p4_mut().next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .unwrap()
+                .free_next_table(page.p2_index(), allocator);
+        }
+
+        let p2_empty = self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .map_or(false, |p2| p2.is_empty());
+
+        if p2_empty {
+            self.p4_mut().next_table_mut(page.p4_index())
+                .unwrap()
+                .free_next_table(page.p3_index(), allocator);
+        }
 
-    pub fn translate(&self, virt_addr: VirtualAddr) -> Option<PhysicalAddr> {
-        let offset = virt_addr % PAGE_SIZE;
+        let p3_empty = page.p4_index() < 256 && self.p4().next_table(page.p4_index())
+            .map_or(false, |p3| p3.is_empty());
+
+        if p3_empty {
+            self.p4_mut().free_next_table(page.p4_index(), allocator);
+        }
+
+        // other address spaces may still cache the freed kernel tables
+        if page.p4_index() >= 256 && (p1_empty || p2_empty) {
+            pcid::kernel_changed();
+        }
+    }
+
+    /// Unmap everything under P4 entry `p4_index` and free the tables below it, releasing frames
+    /// nothing else maps. Huge pages are released frame by frame.
+    pub fn clear_p4_entry<A>(&mut self, p4_index: usize, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        {
+            let p3 = match self.p4_mut().next_table_mut(p4_index) {
+                Some(p3) => p3,
+                None => return,
+            };
+
+            for i3 in 0..ENTRY_COUNT {
+                if p3[i3].flags().contains(PRESENT | HUGE_PAGE) {
+                    let frame = clear_huge_entry(p3, i3, Page::from_table_indices(p4_index, i3, 0, 0).start_addr()).unwrap();
+                    release_huge_frames(frame, Page1G::frame_count(), allocator);
+                    continue;
+                }
+
+                {
+                    let p2 = match p3.next_table_mut(i3) {
+                        Some(p2) => p2,
+                        None => continue,
+                    };
+
+                    for i2 in 0..ENTRY_COUNT {
+                        if p2[i2].flags().contains(PRESENT | HUGE_PAGE) {
+                            let frame = clear_huge_entry(p2, i2, Page::from_table_indices(p4_index, i3, i2, 0).start_addr()).unwrap();
+                            release_huge_frames(frame, ENTRY_COUNT, allocator);
+                            continue;
+                        }
+
+                        {
+                            let p1 = match p2.next_table_mut(i2) {
+                                Some(p1) => p1,
+                                None => continue,
+                            };
+
+                            for i1 in 0..ENTRY_COUNT {
+                                if let Some(frame) = p1[i1].pointed_frame() {
+                                    p1[i1].set_unused();
+                                    p1.decrement_entry_count();
+                                    release_frame(frame, allocator);
+                                }
+                            }
+                        }
+
+                        p2.free_next_table(i2, allocator);
+                    }
+                }
+
+                p3.free_next_table(i3, allocator);
+            }
+        }
+
+        self.p4_mut().free_next_table(p4_index, allocator);
+    }
+
+    /// Recompute the entry count of every table in the hierarchy. The bootloader's tables don't
+    /// carry counts, so this has to run before anything is unmapped.
+    pub fn recount_entries(&mut self) {
+        let p4 = self.p4_mut();
+        p4.recount();
+
+        // skip the recursive entry
+        for i in 0..ENTRY_COUNT - 1 {
+            if let Some(p3) = p4.next_table_mut(i) {
+                p3.recount();
+
+                for j in 0..ENTRY_COUNT {
+                    if let Some(p2) = p3.next_table_mut(j) {
+                        p2.recount();
+
+                        for k in 0..ENTRY_COUNT {
+                            if let Some(p1) = p2.next_table_mut(k) {
+                                p1.recount();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn translate(&self, virt_addr: VirtAddr) -> Option<PhysAddr> {
+        let offset = virt_addr.as_usize() % PAGE_SIZE;
         self.translate_page(Page::containing_addr(virt_addr))
-            .map(|frame| frame.index() * PAGE_SIZE + offset)
+            .map(|frame| frame.start_addr() + offset)
     }
 
 
@@ -89,7 +815,7 @@ impl Mapper {
             p3.and_then(|p3| {
                 let p3_entry = &p3[page.p3_index()];
 
-                if let Some(start_frame) = p3_entry.pointed_frame() {
+                if let Some(start_frame) = p3_entry.pointed_huge_frame() {
                     if p3_entry.flags().contains(HUGE_PAGE) {
                         assert_eq!(start_frame.index() % (ENTRY_COUNT * ENTRY_COUNT), 0);
                         return Some(Frame::new(
@@ -101,7 +827,7 @@ impl Mapper {
                 if let Some(p2) = p3.next_table(page.p3_index()) {
                     let p2_entry = &p2[page.p2_index()];
 
-                    if let Some(start_frame) = p2_entry.pointed_frame() {
+                    if let Some(start_frame) = p2_entry.pointed_huge_frame() {
                         if p2_entry.flags().contains(HUGE_PAGE) {
                             assert_eq!(start_frame.index() % ENTRY_COUNT, 0);
                             return Some(Frame::new(start_frame.index() + page.p1_index()));
@@ -148,3 +874,147 @@ impl Mapper {
         VecFrameSet::from(result)
     }
 }
+
+/// The table level whose entry maps a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Leaf {
+    P1,
+    P2,
+    P3,
+}
+
+impl Leaf {
+    /// First and last 4 KiB page of the mapping at this level that covers `page`.
+    fn span(self, page: Page) -> (Page, Page) {
+        let pages = match self {
+            Leaf::P1 => 1,
+            Leaf::P2 => ENTRY_COUNT,
+            Leaf::P3 => ENTRY_COUNT * ENTRY_COUNT,
+        };
+
+        let first = Page::containing_addr(page.start_addr().align_down(pages * PAGE_SIZE));
+        (first, first + (pages - 1))
+    }
+}
+
+/// Replace a leaf's flags, keeping its frame, its size and the accessed/dirty state. Returns the
+/// previous flags. Both are in 4 KiB form whatever the leaf's size: `PAT` selects the cache type,
+/// and is moved to `HUGE_PAT` on a `huge` leaf.
+fn set_leaf_entry_flags(entry: &mut Entry, flags: EntryFlags, huge: bool) -> EntryFlags {
+    let (old, frame) = if huge {
+        (entry.huge_flags(), entry.pointed_huge_frame().unwrap())
+    } else {
+        (entry.flags(), entry.pointed_frame().unwrap())
+    };
+
+    let state = old & (ACCESSED | DIRTY);
+
+    if !huge {
+        entry.set(frame, flags | PRESENT | state);
+        return old;
+    }
+
+    let previous = entry.leaf_flags(true) | state;
+    let (flags, cache) = CacheType::split(flags);
+
+    entry.set(frame, flags | cache.huge_flags() | PRESENT | HUGE_PAGE | state);
+    previous
+}
+
+/// Leaf flags for a 4 KiB entry cached as `cache`. The flags must not pick a cache type of
+/// their own.
+fn with_cache(flags: EntryFlags, cache: CacheType) -> EntryFlags {
+    debug_assert!(!flags.intersects(PAT | NO_CACHE | WRITE_THROUGH), "cache bits in mapping flags");
+    flags | cache.flags()
+}
+
+/// Loosen an intermediate entry so it doesn't restrict a leaf with `flags` below it. Access is
+/// the intersection of every level, so parents only ever gain permissions here.
+fn upgrade_parent(entry: &mut Entry, flags: EntryFlags) {
+    let old = entry.flags();
+    let mut new = old;
+
+    if flags.contains(WRITABLE) {
+        new.insert(WRITABLE);
+    }
+
+    if flags.contains(USER_ACCESSIBLE) {
+        new.insert(USER_ACCESSIBLE);
+    }
+
+    if !flags.contains(NX) {
+        new.remove(NX);
+    }
+
+    if new != old {
+        let frame = entry.pointed_frame().unwrap();
+        entry.set(frame, new);
+    }
+}
+
+/// Drop a mapping's reference to `frame` and give it back to `allocator` once nothing else maps
+/// it.
+fn release_frame<A>(frame: Frame, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let unused = frame_table::descriptor(&frame).map_or(true, |d| d.dec_ref() == 0);
+
+    if unused && frame_table::allocatable(&frame) {
+        allocator.release(frame);
+    }
+}
+
+/// `release_frame` for a huge page: the reference lives on the first frame, but every frame in
+/// the block goes back to `allocator`.
+fn release_huge_frames<A>(first: Frame, frame_count: usize, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let unused = frame_table::descriptor(&first).map_or(true, |d| d.dec_ref() == 0);
+
+    if unused {
+        (0..frame_count)
+            .map(|i| Frame::new(first.index() + i))
+            .filter(|f| frame_table::allocatable(f))
+            .for_each(|f| allocator.release(f));
+    }
+}
+
+/// Whether changing a leaf's flags from `old` to `new` takes anything away: presence, write or
+/// user access, execution, or the cache type. Both are in 4 KiB form.
+fn is_downgrade(old: EntryFlags, new: EntryFlags) -> bool {
+    let lost = old & !new;
+    let gained = new & !old;
+
+    lost.intersects(PRESENT | WRITABLE | USER_ACCESSIBLE)
+        || gained.contains(NX)
+        || (old ^ new).intersects(PAT | NO_CACHE | WRITE_THROUGH)
+}
+
+fn set_huge_entry<L>(table: &mut Table<L>, index: usize, frame: Frame, flags: EntryFlags, addr: VirtAddr) -> Result<(), MapError>
+    where L: TableLevel
+{
+    if !table[index].unused() {
+        return Err(MapError::AlreadyMapped { addr });
+    }
+
+    frame_table::descriptor(&frame).map(|d| d.inc_ref());
+    table[index].set(frame, flags | PRESENT | HUGE_PAGE);
+    table.increment_entry_count();
+
+    Ok(())
+}
+
+fn clear_huge_entry<L>(table: &mut Table<L>, index: usize, addr: VirtAddr) -> Result<Frame, MapError>
+    where L: TableLevel
+{
+    let frame = table[index].pointed_huge_frame().ok_or(MapError::NotMapped { addr })?;
+
+    if !table[index].flags().contains(HUGE_PAGE) {
+        return Err(MapError::HugePageConflict);
+    }
+
+    table[index].set_unused();
+    table.decrement_entry_count();
+
+    Ok(frame)
+}
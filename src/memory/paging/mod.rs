@@ -1,8 +1,18 @@
 pub use self::active_page_table::ActivePageTable;
 pub use self::entry::*;
 pub use self::inactive_page_table::InactivePageTable;
-pub use self::page::{Page, PageIter};
+pub use self::page::{Page, PageIter, HugePage, Page2M, Page1G, supports_1g_pages};
+pub use self::mapper::{Mapper, MapError, MapperFlush, MapperFlushRange};
+pub use self::addr::{PhysAddr, VirtAddr};
+pub use self::kernel_image::{remap_kernel, enable_nxe};
+pub use self::temporary_page::TemporaryPage;
+pub use self::pcid::Pcid;
+pub use self::pat::CacheType;
+pub use self::dump::{dump, dump_path};
+pub use self::verify::{verify, TableProblem};
+pub use self::scan::{PageUsage, ScanAndClear};
 
+mod addr;
 mod page;
 mod entry;
 mod table;
@@ -10,8 +20,12 @@ mod mapper;
 mod temporary_page;
 mod inactive_page_table;
 mod active_page_table;
+mod kernel_image;
+mod dump;
+mod verify;
+mod scan;
+pub mod physmap;
+pub mod pcid;
+pub mod pat;
 
 const ENTRY_COUNT: usize = 512;
-
-pub type PhysicalAddr = usize;
-pub type VirtualAddr = usize;
@@ -1,7 +1,7 @@
 use core::ops::Add;
 
 use memory::PAGE_SIZE;
-use super::VirtualAddr;
+use super::VirtAddr;
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -10,16 +10,12 @@ pub struct Page {
 }
 
 impl Page {
-    pub fn containing_addr(addr: VirtualAddr) -> Page {
-        assert!(addr < 0x0000_8000_0000_0000
-                    || addr >= 0xffff_8000_0000_0000,
-                "invalid addr: 0x{:x}", addr);
-
-        Page { index: addr / PAGE_SIZE }
+    pub fn containing_addr(addr: VirtAddr) -> Page {
+        Page { index: addr.as_usize() / PAGE_SIZE }
     }
 
-    pub fn start_addr(&self) -> usize {
-        self.index * PAGE_SIZE
+    pub fn start_addr(&self) -> VirtAddr {
+        VirtAddr::new(self.index * PAGE_SIZE)
     }
 
     pub fn range_inclusive(start: Page, end: Page) -> PageIter {
@@ -29,6 +25,18 @@ impl Page {
         }
     }
 
+    /// The page reached through the given entry index at each table level.
+    pub(crate) fn from_table_indices(p4: usize, p3: usize, p2: usize, p1: usize) -> Page {
+        let mut addr = (p4 << 39) | (p3 << 30) | (p2 << 21) | (p1 << 12);
+
+        // sign-extend into the higher half
+        if p4 >= 256 {
+            addr |= 0xffff_0000_0000_0000;
+        }
+
+        Page::containing_addr(VirtAddr::new(addr))
+    }
+
     pub(crate) fn new_from_index(index: usize) -> Page {
         Page {
             index
@@ -60,12 +68,96 @@ impl Add<usize> for Page {
     }
 }
 
+/// A page mapped directly by a P3 or P2 entry rather than through a P1 table.
+pub trait HugePage: Copy {
+    /// Size of the page in bytes.
+    const SIZE: usize;
+
+    fn containing_addr(addr: VirtAddr) -> Self;
+    fn start_addr(&self) -> VirtAddr;
+
+    /// The 4 KiB page at the start of this page.
+    fn first_page(&self) -> Page {
+        Page::containing_addr(self.start_addr())
+    }
+
+    /// Number of 4 KiB frames the page covers.
+    fn frame_count() -> usize {
+        Self::SIZE / PAGE_SIZE
+    }
+}
+
+/// A 2 MiB page, mapped by a P2 entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Page2M {
+    index: usize,
+}
+
+/// A 1 GiB page, mapped by a P3 entry. Only usable if `supports_1g_pages` returns true.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Page1G {
+    index: usize,
+}
+
+impl HugePage for Page2M {
+    const SIZE: usize = PAGE_SIZE * 512;
+
+    fn containing_addr(addr: VirtAddr) -> Page2M {
+        Page2M { index: Page::containing_addr(addr).index / 512 }
+    }
+
+    fn start_addr(&self) -> VirtAddr {
+        VirtAddr::new(self.index * Self::SIZE)
+    }
+}
+
+impl HugePage for Page1G {
+    const SIZE: usize = PAGE_SIZE * 512 * 512;
+
+    fn containing_addr(addr: VirtAddr) -> Page1G {
+        Page1G { index: Page::containing_addr(addr).index / (512 * 512) }
+    }
+
+    fn start_addr(&self) -> VirtAddr {
+        VirtAddr::new(self.index * Self::SIZE)
+    }
+}
+
+/// Whether the CPU can map 1 GiB pages (CPUID pdpe1gb).
+pub fn supports_1g_pages() -> bool {
+    use cpuid::CpuId;
+    use spin::Once;
+
+    static PDPE1GB: Once<bool> = Once::new();
+
+    *PDPE1GB.call_once(|| {
+        CpuId::new().get_extended_function_info()
+            .map_or(false, |info| info.has_1gib_pages())
+    })
+}
+
 #[derive(Clone, Debug)]
 pub struct PageIter {
     start: Page,
     end: Page,
 }
 
+impl PageIter {
+    /// The next page the iterator will yield.
+    pub fn start(&self) -> Page {
+        self.start
+    }
+
+    /// The last page the iterator will yield.
+    pub fn end(&self) -> Page {
+        self.end
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start > self.end
+    }
+}
+
 impl Iterator for PageIter {
     type Item = Page;
 
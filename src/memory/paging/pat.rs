@@ -0,0 +1,142 @@
+use x86_64::registers::msr::{rdmsr, wrmsr};
+
+use super::entry::*;
+
+const IA32_PAT: u32 = 0x277;
+
+// memory type encodings, see 11-12.2, Vol. 3A
+const UC: u64 = 0x00;
+const WC: u64 = 0x01;
+const WT: u64 = 0x04;
+const WP: u64 = 0x05;
+const WB: u64 = 0x06;
+const UC_MINUS: u64 = 0x07;
+
+/// The PAT we run with, entry i in byte i. An entry is selected by the PAT, NO_CACHE and
+/// WRITE_THROUGH bits, in that order:
+///
+///     0: WB  1: WT  2: UC-  3: UC  4: WB  5: WC  6: WP  7: UC
+///
+/// Only entries 5 and 6 differ from the power-on PAT (WT and UC-), so every mapping made before
+/// `init` keeps its type as long as it doesn't set `PAT`, which neither the bootloader nor the
+/// early kernel does. WC and WP therefore need the `PAT` bit, i.e. `HUGE_PAT` in huge entries.
+const PAT_LAYOUT: [u64; 8] = [WB, WT, UC_MINUS, UC, WB, WC, WP, UC];
+
+/// How the CPU may cache accesses through a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteThrough,
+
+    /// Uncacheable, unless the MTRRs say write-combining.
+    UncachedMinus,
+
+    /// Strong uncacheable, as memory-mapped registers need.
+    Uncacheable,
+
+    /// Uncached, but writes are buffered and combined. For framebuffers.
+    WriteCombining,
+
+    /// Reads are cached, writes go to memory and invalidate the line.
+    WriteProtected,
+}
+
+impl CacheType {
+    /// The flags selecting this cache type in a 4 KiB entry.
+    pub fn flags(&self) -> EntryFlags {
+        let (pat, pcd, pwt) = self.selector();
+        pat_flags(pat, pcd, pwt, PAT)
+    }
+
+    /// The flags selecting this cache type in a 2 MiB or 1 GiB entry.
+    pub fn huge_flags(&self) -> EntryFlags {
+        let (pat, pcd, pwt) = self.selector();
+        pat_flags(pat, pcd, pwt, HUGE_PAT)
+    }
+
+    /// The cache type a 4 KiB entry with `flags` selects.
+    pub fn from_flags(flags: EntryFlags) -> CacheType {
+        match (flags.contains(PAT), flags.contains(NO_CACHE), flags.contains(WRITE_THROUGH)) {
+            (false, false, false) | (true, false, false) => CacheType::WriteBack,
+            (false, false, true) => CacheType::WriteThrough,
+            (false, true, false) => CacheType::UncachedMinus,
+            (false, true, true) | (true, true, true) => CacheType::Uncacheable,
+            (true, false, true) => CacheType::WriteCombining,
+            (true, true, false) => CacheType::WriteProtected,
+        }
+    }
+
+    /// Split 4 KiB entry `flags` into the cache type they select and everything else, the form
+    /// the mapping functions take them in.
+    pub fn split(flags: EntryFlags) -> (EntryFlags, CacheType) {
+        (flags & !(PAT | NO_CACHE | WRITE_THROUGH), CacheType::from_flags(flags))
+    }
+
+    /// The usual abbreviation: WB, WT, UC-, UC, WC or WP.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CacheType::WriteBack => "WB",
+            CacheType::WriteThrough => "WT",
+            CacheType::UncachedMinus => "UC-",
+            CacheType::Uncacheable => "UC",
+            CacheType::WriteCombining => "WC",
+            CacheType::WriteProtected => "WP",
+        }
+    }
+
+    /// The PAT, PCD and PWT bits of the `PAT_LAYOUT` entry for this type.
+    fn selector(&self) -> (bool, bool, bool) {
+        match *self {
+            CacheType::WriteBack => (false, false, false),
+            CacheType::WriteThrough => (false, false, true),
+            CacheType::UncachedMinus => (false, true, false),
+            CacheType::Uncacheable => (false, true, true),
+            CacheType::WriteCombining => (true, false, true),
+            CacheType::WriteProtected => (true, true, false),
+        }
+    }
+}
+
+fn pat_flags(pat: bool, pcd: bool, pwt: bool, pat_bit: EntryFlags) -> EntryFlags {
+    let mut flags = EntryFlags::empty();
+
+    if pat {
+        flags.insert(pat_bit);
+    }
+
+    if pcd {
+        flags.insert(NO_CACHE);
+    }
+
+    if pwt {
+        flags.insert(WRITE_THROUGH);
+    }
+
+    flags
+}
+
+/// Program IA32_PAT with `PAT_LAYOUT`. Panics if the CPU has no PAT, which no x86_64 CPU lacks.
+pub fn init() {
+    use cpuid::CpuId;
+    use x86_64::instructions::tlb;
+
+    let has_pat = CpuId::new().get_feature_info().map_or(false, |f| f.has_pat());
+    assert!(has_pat, "PAT not supported");
+
+    let value = PAT_LAYOUT.iter()
+        .enumerate()
+        .fold(0, |pat, (i, ty)| pat | ty << (i * 8));
+
+    // Vol. 3A 11.12.4: caches and TLBs may hold lines of the old types
+    unsafe {
+        let old = rdmsr(IA32_PAT);
+        if old != value {
+            asm!("wbinvd" :::: "volatile");
+            wrmsr(IA32_PAT, value);
+            asm!("wbinvd" :::: "volatile");
+            tlb::flush_all();
+        }
+    }
+
+    println!("PAT programmed to {:#018x}", value);
+}
@@ -0,0 +1,166 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use memory::Frame;
+use super::VirtAddr;
+
+const PCID_COUNT: usize = 4096;
+const WORDS: usize = PCID_COUNT / 64;
+
+/// CR3 bit 63: keep the TLB entries tagged with the new PCID.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+const INVPCID_ADDRESS: u64 = 0;
+const INVPCID_SINGLE: u64 = 1;
+
+/// A process-context identifier tagging TLB entries. PCID 0 belongs to the kernel's own table
+/// and to any address space that couldn't get one of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pcid(u16);
+
+impl Pcid {
+    pub const KERNEL: Pcid = Pcid(0);
+
+    #[inline]
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+struct PcidPool {
+    /// PCIDs in use.
+    used: [u64; WORDS],
+
+    /// PCIDs that may still have TLB entries from a previous owner or from changes made while
+    /// inactive. Loading one of these into CR3 must flush.
+    stale: [u64; WORDS],
+}
+
+static POOL: Mutex<PcidPool> = Mutex::new(PcidPool {
+    used: [0; WORDS],
+    stale: [0; WORDS],
+});
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static INVPCID: AtomicBool = AtomicBool::new(false);
+
+/// Turn on CR4.PCIDE if the CPU supports it. Without it, `alloc` hands out nothing and every
+/// switch flushes the TLB as before. Must run while CR3 holds PCID 0, i.e. before any switch.
+pub fn init() {
+    use cpuid::CpuId;
+
+    let cpuid = CpuId::new();
+    let pcid = cpuid.get_feature_info().map_or(false, |f| f.has_pcid());
+    let invpcid = cpuid.get_extended_feature_info().map_or(false, |f| f.has_invpcid());
+
+    if !pcid {
+        println!("PCID not supported");
+        return;
+    }
+
+    unsafe {
+        let cr4: u64;
+        asm!("mov %cr4, $0" : "=r"(cr4) ::: "volatile");
+        asm!("mov $0, %cr4" :: "r"(cr4 | 1 << 17) : "memory" : "volatile");
+    }
+
+    POOL.lock().used[0] |= 1; // reserve the kernel's PCID
+    INVPCID.store(invpcid, Ordering::Release);
+    ENABLED.store(true, Ordering::Release);
+
+    println!("PCID enabled (INVPCID {})", if invpcid { "supported" } else { "not supported" });
+}
+
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Take a PCID from the pool. `None` if PCIDs are disabled or all are in use.
+pub fn alloc() -> Option<Pcid> {
+    if !enabled() {
+        return None;
+    }
+
+    let mut pool = POOL.lock();
+
+    let word = pool.used.iter().position(|w| *w != !0)?;
+    let bit = (!pool.used[word]).trailing_zeros() as usize;
+
+    pool.used[word] |= 1 << bit;
+    Some(Pcid((word * 64 + bit) as u16))
+}
+
+/// Return `pcid` to the pool. Its TLB entries are dropped before it is next used.
+pub fn free(pcid: Pcid) {
+    assert!(pcid != Pcid::KERNEL, "freeing the kernel PCID");
+
+    let (word, bit) = split(pcid);
+    let mut pool = POOL.lock();
+
+    assert!(pool.used[word] & 1 << bit != 0, "double free of PCID {}", pcid.0);
+    pool.used[word] &= !(1 << bit);
+
+    if INVPCID.load(Ordering::Acquire) {
+        unsafe { invpcid(INVPCID_SINGLE, pcid, 0) };
+    } else {
+        pool.stale[word] |= 1 << bit;
+    }
+}
+
+/// Drop the translation for `addr` from the TLB entries of `pcid`, which need not be active.
+/// Without INVPCID the whole PCID is flushed the next time it's loaded instead.
+pub fn invalidate(pcid: Pcid, addr: VirtAddr) {
+    if INVPCID.load(Ordering::Acquire) {
+        unsafe { invpcid(INVPCID_ADDRESS, pcid, addr.as_u64()) };
+    } else {
+        let (word, bit) = split(pcid);
+        POOL.lock().stale[word] |= 1 << bit;
+    }
+}
+
+/// Record a change to the kernel half. Its tables are shared by every address space but its
+/// entries aren't global, so `invlpg` only drops them for the active PCID; every other PCID in use
+/// is flushed the next time it's loaded.
+pub fn kernel_changed() {
+    if !enabled() {
+        return;
+    }
+
+    let mut guard = POOL.lock();
+    let pool = &mut *guard;
+
+    for (stale, used) in pool.stale.iter_mut().zip(pool.used.iter()) {
+        *stale |= *used;
+    }
+}
+
+/// Load `p4_frame` into CR3 tagged with `pcid`, keeping that PCID's TLB entries unless they are
+/// stale. The kernel PCID is shared by every table without one of its own, so it always flushes.
+pub unsafe fn switch(p4_frame: &Frame, pcid: Pcid) {
+    let (word, bit) = split(pcid);
+
+    let flush = pcid == Pcid::KERNEL || {
+        let mut pool = POOL.lock();
+        let stale = pool.stale[word] & 1 << bit != 0;
+        pool.stale[word] &= !(1 << bit);
+        stale
+    };
+
+    let mut cr3 = p4_frame.start_addr().as_u64() | pcid.0 as u64;
+    if !flush {
+        cr3 |= CR3_NO_FLUSH;
+    }
+
+    asm!("mov $0, %cr3" :: "r"(cr3) : "memory" : "volatile");
+}
+
+fn split(pcid: Pcid) -> (usize, usize) {
+    (pcid.0 as usize / 64, pcid.0 as usize % 64)
+}
+
+unsafe fn invpcid(kind: u64, pcid: Pcid, addr: u64) {
+    let descriptor: [u64; 2] = [pcid.0 as u64, addr];
+    asm!("invpcid ($0), $1" :: "r"(&descriptor), "r"(kind) : "memory" : "volatile");
+}
@@ -0,0 +1,151 @@
+use core::{cmp, ptr};
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::Vec;
+
+use bootinfo::{MemoryMap, MemoryRegionType};
+
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use super::{ActivePageTable, Page, Page2M, HugePage, PhysAddr, VirtAddr};
+use super::entry::*;
+use super::mapper::MapError;
+
+/// Start of the direct map: physical address `p` is visible at `PHYSMAP_BASE + p`. This is P4
+/// entry 384, well away from the kernel image and its heap in entry 256.
+pub const PHYSMAP_BASE: VirtAddr = VirtAddr::new_unchecked(0xffff_c000_0000_0000);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the direct map is up. Once it is, page tables are walked through it instead of the
+/// recursive slot.
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Address of `addr` in the direct map. Panics if the direct map isn't set up.
+#[inline]
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    assert!(enabled(), "physmap is not enabled");
+    PHYSMAP_BASE + addr.as_usize()
+}
+
+/// Zero `frame` through the direct map, without mapping it anywhere.
+pub fn zero_frame(frame: &Frame) {
+    unsafe { ptr::write_bytes(phys_to_virt(frame.start_addr()).as_mut_ptr::<u8>(), 0, PAGE_SIZE); }
+}
+
+/// Map every RAM region in `memory_map` at `PHYSMAP_BASE`, then switch table walking over to
+/// it. Runs of RAM are mapped with 2 MiB pages where they cover whole ones and 4 KiB pages at
+/// their edges.
+///
+/// Only RAM is mapped, since a write-back alias of MMIO or firmware memory would conflict with
+/// its real cache type. Anything else has to go through `mmio::map_mmio`.
+///
+/// The direct map doesn't own any of the frames it covers, so their reference counts are left
+/// alone; only the tables built for it come from `allocator`.
+pub fn init<A>(active_table: &mut ActivePageTable, memory_map: &MemoryMap, allocator: &mut A)
+    where A: FrameAllocator
+{
+    assert!(!enabled(), "physmap is already enabled");
+
+    let mut regions = memory_map.iter()
+        .filter(|reg| is_ram(reg.region_type))
+        .map(|reg| (reg.range.start_addr() as usize, reg.range.end_addr() as usize))
+        .collect::<Vec<_>>();
+
+    regions.sort();
+
+    // merge adjacent regions so runs crossing a region boundary still get huge pages
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in regions {
+        if runs.last().map_or(false, |run| start <= run.1) {
+            let run = runs.last_mut().unwrap();
+            run.1 = cmp::max(run.1, end);
+        } else {
+            runs.push((start, end));
+        }
+    }
+
+    let mut mapped = 0;
+    for &(start, end) in runs.iter() {
+        map_run(active_table, start, end, allocator).expect("unable to map physmap");
+        mapped += end - start;
+    }
+
+    println!("mapped {:#x} bytes of physical memory in {} runs at {:#x}", mapped, runs.len(), PHYSMAP_BASE);
+
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Whether a region of type `ty` is ordinary RAM, which is safe to alias write-back. ACPI NVS,
+/// bad memory and anything the bootloader doesn't know about aren't.
+fn is_ram(ty: MemoryRegionType) -> bool {
+    match ty {
+        MemoryRegionType::Usable |
+        MemoryRegionType::InUse |
+        MemoryRegionType::Kernel |
+        MemoryRegionType::KernelStack |
+        MemoryRegionType::PageTable |
+        MemoryRegionType::Bootloader |
+        MemoryRegionType::BootInfo |
+        MemoryRegionType::AcpiReclaimable => true,
+        _ => false,
+    }
+}
+
+/// Map physical `start..end` (page aligned) into the direct map.
+fn map_run<A>(active_table: &mut ActivePageTable, start: usize, end: usize, allocator: &mut A) -> Result<(), MapError>
+    where A: FrameAllocator
+{
+    let mut offset = start;
+
+    while offset < end {
+        let page = Page::containing_addr(PHYSMAP_BASE + offset);
+        let frame = Frame::containing_addr(PhysAddr::new(offset));
+
+        if offset % Page2M::SIZE == 0 && end - offset >= Page2M::SIZE {
+            map_direct_huge(active_table, page, frame, allocator)?;
+            offset += Page2M::SIZE;
+        } else {
+            map_direct(active_table, page, frame, allocator)?;
+            offset += PAGE_SIZE;
+        }
+    }
+
+    Ok(())
+}
+
+fn map_direct<A>(active_table: &mut ActivePageTable, page: Page, frame: Frame, allocator: &mut A) -> Result<(), MapError>
+    where A: FrameAllocator
+{
+    let p1 = active_table.p4_mut()
+        .next_table_create(page.p4_index(), allocator)?
+        .next_table_create(page.p3_index(), allocator)?
+        .next_table_create(page.p2_index(), allocator)?;
+
+    if !p1[page.p1_index()].unused() {
+        return Err(MapError::AlreadyMapped { addr: page.start_addr() });
+    }
+
+    p1[page.p1_index()].set(frame, PRESENT | WRITABLE | NX);
+    p1.increment_entry_count();
+
+    Ok(())
+}
+
+fn map_direct_huge<A>(active_table: &mut ActivePageTable, page: Page, frame: Frame, allocator: &mut A) -> Result<(), MapError>
+    where A: FrameAllocator
+{
+    let p2 = active_table.p4_mut()
+        .next_table_create(page.p4_index(), allocator)?
+        .next_table_create(page.p3_index(), allocator)?;
+
+    if !p2[page.p2_index()].unused() {
+        return Err(MapError::AlreadyMapped { addr: page.start_addr() });
+    }
+
+    p2[page.p2_index()].set(frame, PRESENT | WRITABLE | NX | HUGE_PAGE);
+    p2.increment_entry_count();
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use memory::PAGE_SIZE;
+use super::{Mapper, MapperFlushRange, Page, PageIter};
+use super::entry::*;
+
+/// Whether a page was accessed or written since its bits were last cleared.
+#[derive(Debug, Clone, Copy)]
+pub struct PageUsage {
+    pub page: Page,
+    pub accessed: bool,
+    pub dirty: bool,
+}
+
+/// Iterator returned by `Mapper::scan_and_clear`. The TLB entries of the pages it cleared are
+/// invalidated in one batch when it's dropped.
+pub struct ScanAndClear<'a> {
+    mapper: &'a mut Mapper,
+    pages: PageIter,
+
+    /// First and last page whose bits were cleared.
+    cleared: Option<(Page, Page)>,
+}
+
+impl Mapper {
+    /// Report the accessed/dirty state of every page in `pages` mapped by a 4 KiB entry, and
+    /// clear both bits so the next scan sees only new activity. Unmapped and huge pages are
+    /// skipped. The batched invalidation only reaches the current TLB, so `self` should be the
+    /// active table.
+    pub fn scan_and_clear(&mut self, pages: PageIter) -> ScanAndClear {
+        ScanAndClear {
+            mapper: self,
+            pages,
+            cleared: None,
+        }
+    }
+}
+
+impl<'a> Iterator for ScanAndClear<'a> {
+    type Item = PageUsage;
+
+    fn next(&mut self) -> Option<PageUsage> {
+        while let Some(page) = self.pages.next() {
+            let usage = {
+                let entry = match self.mapper.p1_mut(page) {
+                    Ok(p1) => &mut p1[page.p1_index()],
+                    Err(_) => continue,
+                };
+
+                let flags = entry.flags();
+                if !flags.contains(PRESENT) {
+                    continue;
+                }
+
+                if flags.intersects(ACCESSED | DIRTY) {
+                    let frame = entry.pointed_frame().unwrap();
+                    entry.set(frame, flags & !(ACCESSED | DIRTY));
+                }
+
+                PageUsage {
+                    page,
+                    accessed: flags.contains(ACCESSED),
+                    dirty: flags.contains(DIRTY),
+                }
+            };
+
+            if usage.accessed || usage.dirty {
+                self.cleared = match self.cleared {
+                    Some((first, _)) => Some((first, page)),
+                    None => Some((page, page)),
+                };
+            }
+
+            return Some(usage);
+        }
+
+        None
+    }
+}
+
+impl<'a> Drop for ScanAndClear<'a> {
+    fn drop(&mut self) {
+        // until the stale entries are gone, the CPU won't set the bits again
+        if let Some((first, last)) = self.cleared {
+            MapperFlushRange::new(first, (last.start_addr() - first.start_addr()) / PAGE_SIZE + 1).flush();
+        }
+    }
+}
@@ -3,8 +3,10 @@ use core::ops::{Index, IndexMut};
 use alloc::Vec;
 
 use memory::frame_allocator::FrameAllocator;
+use memory::frame_table;
 use super::entry::*;
-use super::ENTRY_COUNT;
+use super::mapper::MapError;
+use super::{physmap, ENTRY_COUNT};
 
 pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
 
@@ -16,6 +18,40 @@ pub struct Table<L: TableLevel> {
 impl<L> Table<L> where L: TableLevel {
     pub fn zero(&mut self) {
         self.entries.iter_mut().for_each(|x| x.set_unused());
+        self.set_entry_count(0);
+    }
+
+    /// Number of entries in use, as tracked in the unused bits of entries 0 (low bits) and 1.
+    pub fn entry_count(&self) -> usize {
+        self.entries[0].counter() | self.entries[1].counter() << COUNTER_BITS
+    }
+
+    fn set_entry_count(&mut self, count: usize) {
+        assert!(count <= ENTRY_COUNT);
+        self.entries[0].set_counter(count & ((1 << COUNTER_BITS) - 1));
+        self.entries[1].set_counter(count >> COUNTER_BITS);
+    }
+
+    pub fn increment_entry_count(&mut self) {
+        let count = self.entry_count();
+        self.set_entry_count(count + 1);
+    }
+
+    pub fn decrement_entry_count(&mut self) {
+        let count = self.entry_count();
+        assert!(count > 0, "page table entry count underflow");
+        self.set_entry_count(count - 1);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entry_count() == 0
+    }
+
+    /// Recompute the entry count from the table's contents. Tables we didn't build ourselves
+    /// (i.e. the bootloader's) don't carry a count until this runs.
+    pub fn recount(&mut self) {
+        let count = self.entries.iter().filter(|e| !e.unused()).count();
+        self.set_entry_count(count);
     }
 
     pub fn iter(&self) -> impl Iterator<Item=&Entry> + Clone {
@@ -28,6 +64,10 @@ impl <L: HierarchicalLevel> Table<L> {
     fn next_table_addr(&self, index: usize) -> Option<usize> {
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
+            if physmap::enabled() {
+                return self[index].pointed_frame().map(|f| physmap::phys_to_virt(f.start_addr()).as_usize());
+            }
+
             let table_addr = self as *const _ as usize;
             Some((table_addr << 9) | (index << 12))
         } else {
@@ -45,17 +85,48 @@ impl <L: HierarchicalLevel> Table<L> {
             .map(|addr| unsafe { &mut *(addr as *mut _) })
     }
 
-    pub fn next_table_create<A>(&mut self, index: usize, allocator: &mut A) -> &mut Table<L::NextLevel>
+    pub fn next_table_create<A>(&mut self, index: usize, allocator: &mut A) -> Result<&mut Table<L::NextLevel>, MapError>
         where A: FrameAllocator
     {
         if self.next_table(index).is_none() {
-            assert!(!self.entries[index].flags().contains(HUGE_PAGE),
-                "no support for huge pages");
-            let frame = allocator.alloc().expect("no frames available");
+            if self.entries[index].flags().contains(HUGE_PAGE) {
+                return Err(MapError::HugePageConflict);
+            }
+
+            let frame = allocator.alloc().ok_or(MapError::OutOfFrames)?;
+            frame_table::descriptor(&frame).map(|d| d.insert_flags(frame_table::PAGE_TABLE));
             self.entries[index].set(frame, PRESENT | WRITABLE);
+            self.increment_entry_count();
             self.next_table_mut(index).unwrap().zero();
         }
-        self.next_table_mut(index).unwrap()
+
+        Ok(self.next_table_mut(index).unwrap())
+    }
+
+    /// Unlink the (empty) table at `index` and give its frame back to `allocator`.
+    pub fn free_next_table<A>(&mut self, index: usize, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        let table_addr = self.next_table_addr(index).expect("no page table to free");
+        assert!(self.next_table(index).unwrap().is_empty(), "freeing a page table that is still in use");
+
+        let frame = self.entries[index].pointed_frame().unwrap();
+        self.entries[index].set_unused();
+        self.decrement_entry_count();
+
+        // drop the table's recursive mapping from the TLB. the direct map never changes.
+        if !physmap::enabled() {
+            tlb::flush(VirtualAddress(table_addr));
+        }
+
+        frame_table::descriptor(&frame).map(|d| d.remove_flags(frame_table::PAGE_TABLE));
+
+        if frame_table::allocatable(&frame) {
+            allocator.release(frame);
+        }
     }
 
     pub fn children(&self) -> Vec<&Table<L::NextLevel>> {
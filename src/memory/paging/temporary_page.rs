@@ -1,6 +1,7 @@
 use memory::{Frame, FrameAllocator};
-use super::{ActivePageTable, Page, VirtualAddr};
+use super::{ActivePageTable, Page, VirtAddr};
 use super::table::{Level1, Table};
+use super::pat::CacheType;
 
 pub struct TemporaryPage {
     page: Page,
@@ -19,24 +20,31 @@ impl TemporaryPage {
         }
     }
 
-    pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> VirtualAddr {
+    pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> VirtAddr {
         use super::entry::{PRESENT, WRITABLE};
 
         assert!(active_table.translate_page(self.page).is_none(),
             "page is already mapped");
 
-        active_table.map_to(self.page, frame, PRESENT | WRITABLE, &mut self.alloc);
+        active_table.map_to(self.page, frame, PRESENT | WRITABLE, CacheType::WriteBack, &mut self.alloc)
+            .expect("unable to map temporary page")
+            .flush();
+
         self.page.start_addr()
     }
 
     // use level1 table to forbid calling next_table
     pub fn map_table_frame(&mut self, frame: Frame, active_table: &mut ActivePageTable)
         -> &mut Table<Level1> {
-        unsafe { &mut *(self.map(frame, active_table) as *mut Table<Level1>) }
+        unsafe { &mut *self.map(frame, active_table).as_mut_ptr::<Table<Level1>>() }
     }
 
+    /// The frame stays with whoever owns it; only tables emptied by the unmap go back to the
+    /// tiny allocator.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.alloc)
+        active_table.unmap_keep_frame(self.page, &mut self.alloc)
+            .expect("unable to unmap temporary page")
+            .flush();
     }
 }
 
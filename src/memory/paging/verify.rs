@@ -0,0 +1,265 @@
+use bootinfo::MemoryMap;
+use spin::Mutex;
+
+use memory::{mmio, Frame, PAGE_SIZE};
+use super::{physmap, CacheType, Mapper, Page, PhysAddr, VirtAddr, ENTRY_COUNT};
+use super::entry::*;
+
+/// Most page tables `verify` can check for sharing. Past this, sharing isn't checked.
+const MAX_TABLES: usize = 4096;
+
+/// The legacy area below 1 MiB holds device memory (VGA, BIOS) that memory maps leave out.
+const LEGACY_END: u64 = 0x10_0000;
+
+struct Tables {
+    /// Frame indices of the tables seen so far, sorted.
+    frames: [usize; MAX_TABLES],
+    len: usize,
+    overflowed: bool,
+}
+
+/// Static rather than on the stack or heap: `verify` runs before the heap is up.
+static TABLES: Mutex<Tables> = Mutex::new(Tables {
+    frames: [0; MAX_TABLES],
+    len: 0,
+    overflowed: false,
+});
+
+impl Tables {
+    /// Record `frame` as a table. False if it's already been seen.
+    fn insert(&mut self, frame: &Frame) -> bool {
+        let i = match self.frames[..self.len].binary_search(&frame.index()) {
+            Ok(_) => return false,
+            Err(i) => i,
+        };
+
+        if self.len == MAX_TABLES {
+            self.overflowed = true;
+            return true;
+        }
+
+        for j in (i..self.len).rev() {
+            self.frames[j + 1] = self.frames[j];
+        }
+
+        self.frames[i] = frame.index();
+        self.len += 1;
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, Fail)]
+pub enum TableProblem {
+    #[fail(display = "reserved bits set: {:#x}", bits)]
+    ReservedBits { bits: u64 },
+
+    #[fail(display = "leaf selects PAT entry {}, which no cache type uses", index)]
+    UnusedPatEntry { index: usize },
+
+    #[fail(display = "HUGE_PAGE set on a P4 entry")]
+    HugeP4Entry,
+
+    #[fail(display = "user-accessible page under a kernel-only parent")]
+    UserPageUnderKernelParent,
+
+    #[fail(display = "frame {:#x} is outside the memory map", addr)]
+    FrameOutsideMemoryMap { addr: PhysAddr },
+
+    #[fail(display = "table {:#x} has more than one parent", addr)]
+    SharedTable { addr: PhysAddr },
+}
+
+/// Walk every present entry of `mapper` and print whatever looks wrong. Returns the number of
+/// problems found.
+///
+/// Bit 7 of a P1 entry selects the cache type rather than being a misplaced `HUGE_PAGE`, so it's
+/// only reported when the PAT entry it picks is one `CacheType` never uses. Frames outside
+/// `memory_map` are allowed in the direct map, below 1 MiB and in regions from `map_mmio`.
+pub fn verify(mapper: &Mapper, memory_map: &MemoryMap) -> usize {
+    let mut tables = TABLES.lock();
+    tables.len = 0;
+    tables.overflowed = false;
+
+    let mut verifier = Verifier {
+        memory_map,
+        tables: &mut *tables,
+        reserved: reserved_bits(),
+        problems: 0,
+    };
+
+    let p4 = mapper.p4();
+
+    // skip the recursive entry
+    for i4 in 0..ENTRY_COUNT - 1 {
+        let e4 = &p4[i4];
+        let addr = Page::from_table_indices(i4, 0, 0, 0).start_addr();
+
+        if !verifier.check_entry(addr, e4) {
+            continue;
+        }
+
+        if e4.flags().contains(HUGE_PAGE) {
+            verifier.report(addr, TableProblem::HugeP4Entry);
+            continue;
+        }
+
+        verifier.check_table(addr, e4);
+
+        let direct = physmap::enabled() && i4 == Page::containing_addr(physmap::PHYSMAP_BASE).p4_index();
+        let user4 = e4.flags().contains(USER_ACCESSIBLE);
+        let p3 = p4.next_table(i4).unwrap();
+
+        for i3 in 0..ENTRY_COUNT {
+            let e3 = &p3[i3];
+            let addr = Page::from_table_indices(i4, i3, 0, 0).start_addr();
+
+            if !verifier.check_entry(addr, e3) {
+                continue;
+            }
+
+            let user3 = user4 && e3.flags().contains(USER_ACCESSIBLE);
+
+            if e3.flags().contains(HUGE_PAGE) {
+                verifier.check_huge_leaf(addr, e3, ENTRY_COUNT * ENTRY_COUNT, user4, direct);
+                continue;
+            }
+
+            verifier.check_table(addr, e3);
+            let p2 = p3.next_table(i3).unwrap();
+
+            for i2 in 0..ENTRY_COUNT {
+                let e2 = &p2[i2];
+                let addr = Page::from_table_indices(i4, i3, i2, 0).start_addr();
+
+                if !verifier.check_entry(addr, e2) {
+                    continue;
+                }
+
+                if e2.flags().contains(HUGE_PAGE) {
+                    verifier.check_huge_leaf(addr, e2, ENTRY_COUNT, user3, direct);
+                    continue;
+                }
+
+                verifier.check_table(addr, e2);
+
+                let user2 = user3 && e2.flags().contains(USER_ACCESSIBLE);
+                let p1 = p2.next_table(i2).unwrap();
+
+                for i1 in 0..ENTRY_COUNT {
+                    let e1 = &p1[i1];
+                    let addr = Page::from_table_indices(i4, i3, i2, i1).start_addr();
+
+                    if !verifier.check_entry(addr, e1) {
+                        continue;
+                    }
+
+                    let frame = e1.pointed_frame().unwrap();
+                    verifier.check_leaf(addr, &frame, e1.leaf_flags(false), user2, direct);
+                }
+            }
+        }
+    }
+
+    if verifier.tables.overflowed {
+        println!("page table verification: more than {} tables, sharing only partly checked", MAX_TABLES);
+    }
+
+    println!("page table verification: {} problem(s)", verifier.problems);
+    verifier.problems
+}
+
+struct Verifier<'a> {
+    memory_map: &'a MemoryMap,
+    tables: &'a mut Tables,
+
+    /// Address bits past the CPU's physical address width, which must be clear.
+    reserved: u64,
+
+    problems: usize,
+}
+
+impl<'a> Verifier<'a> {
+    fn report(&mut self, addr: VirtAddr, problem: TableProblem) {
+        println!("page table entry for {:#x}: {}", addr, problem);
+        self.problems += 1;
+    }
+
+    /// Checks common to every present entry. False if it isn't present.
+    fn check_entry(&mut self, addr: VirtAddr, entry: &Entry) -> bool {
+        if !entry.flags().contains(PRESENT) {
+            return false;
+        }
+
+        let bits = entry.raw() & self.reserved;
+        if bits != 0 {
+            self.report(addr, TableProblem::ReservedBits { bits });
+        }
+
+        true
+    }
+
+    /// Checks for a P4, P3 or P2 entry pointing at a table.
+    fn check_table(&mut self, addr: VirtAddr, entry: &Entry) {
+        let frame = entry.pointed_frame().unwrap();
+
+        if !self.tables.insert(&frame) {
+            self.report(addr, TableProblem::SharedTable { addr: frame.start_addr() });
+        }
+
+        self.check_in_memory_map(addr, &frame, false);
+    }
+
+    fn check_huge_leaf(&mut self, addr: VirtAddr, entry: &Entry, frame_count: usize, user_parents: bool, direct: bool) {
+        let frame = entry.pointed_huge_frame().unwrap();
+
+        // the address bits below the page size are reserved, except bit 12 (HUGE_PAT). a frame
+        // that isn't aligned to the page size shows up here.
+        let low = (frame_count * PAGE_SIZE - 1) as u64 & !(PAGE_SIZE as u64 - 1) & !HUGE_PAT.bits();
+        let bits = entry.raw() & low;
+        if bits != 0 {
+            self.report(addr, TableProblem::ReservedBits { bits });
+        }
+
+        self.check_leaf(addr, &frame, entry.leaf_flags(true), user_parents, direct);
+    }
+
+    fn check_leaf(&mut self, addr: VirtAddr, frame: &Frame, flags: EntryFlags, user_parents: bool, direct: bool) {
+        if flags.contains(USER_ACCESSIBLE) && !user_parents {
+            self.report(addr, TableProblem::UserPageUnderKernelParent);
+        }
+
+        let selector = flags & (PAT | NO_CACHE | WRITE_THROUGH);
+        if CacheType::from_flags(flags).flags() != selector {
+            let index = (selector.contains(PAT) as usize) << 2 | (selector.contains(NO_CACHE) as usize) << 1 | selector.contains(WRITE_THROUGH) as usize;
+            self.report(addr, TableProblem::UnusedPatEntry { index });
+        }
+
+        self.check_in_memory_map(addr, frame, direct || mmio::is_mmio(frame));
+    }
+
+    fn check_in_memory_map(&mut self, addr: VirtAddr, frame: &Frame, exempt: bool) {
+        let phys = frame.start_addr().as_u64();
+
+        if exempt || phys < LEGACY_END {
+            return;
+        }
+
+        let mapped = self.memory_map.iter()
+            .any(|reg| reg.range.start_addr() <= phys && phys < reg.range.end_addr());
+
+        if !mapped {
+            self.report(addr, TableProblem::FrameOutsideMemoryMap { addr: frame.start_addr() });
+        }
+    }
+}
+
+/// Bits MAXPHYADDR..51 of an entry.
+fn reserved_bits() -> u64 {
+    use cpuid::CpuId;
+
+    let width = CpuId::new().get_extended_function_info()
+        .and_then(|info| info.physical_address_bits())
+        .map_or(52, |bits| bits as u64);
+
+    ((1 << 52) - 1) & !((1 << width) - 1)
+}
@@ -1,71 +1,128 @@
-use memory::paging::{self, Page, ActivePageTable, PageIter};
+use spin::Mutex;
+
+use memory::paging::{self, ActivePageTable, Page, PageIter, VirtAddr};
 use memory::{PAGE_SIZE, FrameAllocator};
+use memory::virt_allocator::{VirtAllocator, VirtAllocError};
+
+/// Most stacks whose guard pages are tracked at once.
+const MAX_GUARDS: usize = 64;
+
+/// Where each live stack's guard page is, so the page fault handler can name an overflow. Kept
+/// outside the memory controller: a stack can overflow while the controller is locked.
+static GUARDS: Mutex<[Option<StackGuard>; MAX_GUARDS]> = Mutex::new([None; MAX_GUARDS]);
 
+/// The unmapped page below a stack.
+#[derive(Debug, Clone, Copy)]
+pub struct StackGuard {
+    pub id: usize,
+    pub bottom: VirtAddr,
+    pub top: VirtAddr,
+}
+
+impl StackGuard {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.bottom - PAGE_SIZE && addr < self.bottom
+    }
+}
+
+/// The stack whose guard page contains `addr`, if any. `None` if the records are locked, since
+/// this runs in the page fault handler.
+pub fn overflowed_stack(addr: VirtAddr) -> Option<StackGuard> {
+    GUARDS.try_lock()
+        .and_then(|guards| guards.iter().filter_map(|g| *g).find(|g| g.contains(addr)))
+}
+
+/// Allocates kernel stacks. Address space comes from the kernel's `VirtAllocator`, which leaves
+/// an unmapped guard page below every stack.
 pub struct StackAllocator {
-    range: PageIter,
+    next_id: usize,
 }
 
 impl StackAllocator {
-    pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+    pub fn new() -> StackAllocator {
+        StackAllocator { next_id: 0 }
     }
 
     pub fn alloc<A: FrameAllocator>(&mut self,
                                     active_table: &mut ActivePageTable,
                                     frame_allocator: &mut A,
+                                    virt_allocator: &mut VirtAllocator,
                                     size_in_pages: usize) -> Option<Stack> {
         if size_in_pages == 0 {
             return None;
         }
 
-        let mut range = self.range.clone();
-
-        let guard_page = range.next();
-        let stack_start = range.next();
-        let stack_end = if size_in_pages == 1 {
-            stack_start
-        } else {
-            range.nth(size_in_pages - 2)
-        };
-
-        match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
-                self.range = range;
-
-                for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, paging::WRITABLE, frame_allocator);
-                }
-
-                let top_of_stack = end.start_addr() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_addr()))
-            }
-            _ => None
-            // TODO: unmap used pages if allocation failed
+        // claim a guard slot before mapping anything, so running out of slots leaves nothing to
+        // undo. the page fault handler only try_locks GUARDS, so holding it here is safe.
+        let mut guards = GUARDS.lock();
+        let slot = guards.iter_mut().find(|g| g.is_none())?;
+
+        let range = virt_allocator.alloc_mapped(size_in_pages, paging::WRITABLE | paging::NX, active_table, frame_allocator).ok()?;
+
+        let top_of_stack = range.end().start_addr() + PAGE_SIZE;
+        let stack = Stack::new(self.next_id, top_of_stack, range.start().start_addr());
+        self.next_id += 1;
+
+        *slot = Some(StackGuard {
+            id: stack.id,
+            bottom: stack.bottom,
+            top: stack.top,
+        });
+
+        Some(stack)
+    }
+
+    /// Unmap `stack`, release its frames and give its address range (with the guard page) back
+    /// to `virt_allocator`.
+    pub fn free<A: FrameAllocator>(&mut self,
+                                   stack: Stack,
+                                   active_table: &mut ActivePageTable,
+                                   frame_allocator: &mut A,
+                                   virt_allocator: &mut VirtAllocator) -> Result<(), VirtAllocError> {
+        {
+            let mut guards = GUARDS.lock();
+            let slot = guards.iter_mut()
+                .find(|g| g.map_or(false, |g| g.id == stack.id))
+                .expect("freeing an unknown stack");
+
+            *slot = None;
         }
+
+        virt_allocator.free_mapped(stack.pages(), active_table, frame_allocator)
     }
 }
 
 
 #[derive(Debug)]
 pub struct Stack {
-    top: usize,
-    bottom: usize,
+    id: usize,
+    top: VirtAddr,
+    bottom: VirtAddr,
 }
 
 impl Stack {
-    fn new(top: usize, bottom: usize) -> Stack {
+    fn new(id: usize, top: VirtAddr, bottom: VirtAddr) -> Stack {
         assert!(top > bottom);
         Stack{
+            id,
             top,
             bottom,
         }
     }
 
-    pub fn top(&self) -> usize {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn top(&self) -> VirtAddr {
         self.top
     }
 
-    pub fn bottom(&self) -> usize {
+    pub fn bottom(&self) -> VirtAddr {
         self.bottom
     }
+
+    fn pages(&self) -> PageIter {
+        Page::range_inclusive(Page::containing_addr(self.bottom), Page::containing_addr(self.top - PAGE_SIZE))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,265 @@
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use memory::paging::{ActivePageTable, CacheType, EntryFlags, MapError, Page, PageIter, VirtAddr};
+
+/// Most disjoint free ranges tracked at once. Freeing a range that would need more fails.
+const MAX_RANGES: usize = 64;
+
+#[derive(Debug, Clone, Copy, Fail)]
+pub enum VirtAllocError {
+    #[fail(display = "too many free virtual ranges to take back {:#x}", addr)]
+    TooManyRanges { addr: VirtAddr },
+}
+
+/// Hands out ranges of kernel virtual address space, like vmalloc. Every range is preceded by an
+/// unmapped guard page, so neighbouring ranges never touch. Free ranges live in a fixed array
+/// rather than on the heap, since the heap's own range comes from here.
+pub struct VirtAllocator {
+    /// Free ranges as (start, page count), sorted by address and never adjacent.
+    ranges: [(VirtAddr, usize); MAX_RANGES],
+    len: usize,
+}
+
+impl VirtAllocator {
+    /// An allocator owning `start..=end`.
+    pub fn new(start: Page, end: Page) -> VirtAllocator {
+        let mut allocator = VirtAllocator {
+            ranges: [(VirtAddr::new_unchecked(0), 0); MAX_RANGES],
+            len: 0,
+        };
+
+        allocator.insert(0, start.start_addr(), (end.start_addr() - start.start_addr()) / PAGE_SIZE + 1)
+            .expect("empty allocator has no room");
+        allocator
+    }
+
+    /// Reserve `pages` pages (plus a guard page in front). Nothing is mapped.
+    pub fn alloc(&mut self, pages: usize) -> Option<PageIter> {
+        if pages == 0 {
+            return None;
+        }
+
+        let needed = pages + 1;
+        let i = (0..self.len).find(|&i| self.ranges[i].1 >= needed)?;
+        let start = self.ranges[i].0;
+
+        if self.ranges[i].1 == needed {
+            self.remove(i);
+        } else {
+            self.ranges[i] = (start + needed * PAGE_SIZE, self.ranges[i].1 - needed);
+        }
+
+        let guard = Page::containing_addr(start);
+        Some(Page::range_inclusive(guard + 1, guard + pages))
+    }
+
+    /// Give back a range from `alloc`, along with its guard page. It must already be unmapped.
+    /// If it touches no free range and the table is full, the range stays reserved and
+    /// `TooManyRanges` is returned.
+    pub fn free(&mut self, range: PageIter) -> Result<(), VirtAllocError> {
+        let start = range.start().start_addr() - PAGE_SIZE;
+        let pages = (range.end().start_addr() - range.start().start_addr()) / PAGE_SIZE + 2;
+        let end = start + pages * PAGE_SIZE;
+
+        let i = (0..self.len).find(|&i| self.ranges[i].0 > start).unwrap_or(self.len);
+
+        if i > 0 {
+            let (prev_start, prev_pages) = self.ranges[i - 1];
+            assert!(prev_start + prev_pages * PAGE_SIZE <= start, "freeing a free virtual range: {:#x}", start);
+        }
+
+        if i < self.len {
+            assert!(end <= self.ranges[i].0, "freeing a free virtual range: {:#x}", start);
+        }
+
+        let merge_prev = i > 0 && self.ranges[i - 1].0 + self.ranges[i - 1].1 * PAGE_SIZE == start;
+        let merge_next = i < self.len && self.ranges[i].0 == end;
+
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.ranges[i - 1].1 += pages + self.ranges[i].1;
+                self.remove(i);
+            },
+            (true, false) => self.ranges[i - 1].1 += pages,
+            (false, true) => self.ranges[i] = (start, pages + self.ranges[i].1),
+            (false, false) => self.insert(i, start, pages)?,
+        }
+
+        Ok(())
+    }
+
+    /// Reserve a range and map it to fresh frames.
+    pub fn alloc_mapped<A>(&mut self, pages: usize, flags: EntryFlags, active_table: &mut ActivePageTable, allocator: &mut A)
+        -> Result<PageIter, MapError>
+        where A: FrameAllocator
+    {
+        let range = self.alloc(pages).ok_or(MapError::OutOfAddressSpace)?;
+
+        match active_table.map_range(range.clone(), flags, CacheType::WriteBack, allocator) {
+            Ok(flush) => {
+                flush.flush();
+                Ok(range)
+            },
+            Err(e) => {
+                self.free(range).expect("unable to free a range just allocated");
+                Err(e)
+            },
+        }
+    }
+
+    /// Unmap a range from `alloc_mapped`, release its frames and free the range.
+    pub fn free_mapped<A>(&mut self, range: PageIter, active_table: &mut ActivePageTable, allocator: &mut A)
+        -> Result<(), VirtAllocError>
+        where A: FrameAllocator
+    {
+        active_table.unmap_range(range.clone(), allocator)
+            .expect("unable to unmap virtual range")
+            .flush();
+
+        self.free(range)
+    }
+
+    /// Map `frames`, which need not be contiguous, into one contiguous virtual range.
+    pub fn vmap<A, I>(&mut self, frames: I, flags: EntryFlags, cache: CacheType, active_table: &mut ActivePageTable, allocator: &mut A)
+        -> Result<PageIter, MapError>
+        where A: FrameAllocator, I: ExactSizeIterator<Item=Frame>
+    {
+        let range = self.alloc(frames.len()).ok_or(MapError::OutOfAddressSpace)?;
+
+        match active_table.map_range_to(range.clone(), frames, flags, cache, allocator) {
+            Ok(flush) => {
+                flush.flush();
+                Ok(range)
+            },
+            Err(e) => {
+                self.free(range).expect("unable to free a range just allocated");
+                Err(e)
+            },
+        }
+    }
+
+    /// Undo `vmap`. The frames stay with the caller.
+    pub fn vunmap<A>(&mut self, range: PageIter, active_table: &mut ActivePageTable, allocator: &mut A)
+        -> Result<(), VirtAllocError>
+        where A: FrameAllocator
+    {
+        active_table.unmap_range_keep_frames(range.clone(), allocator)
+            .expect("unable to unmap virtual range")
+            .flush();
+
+        self.free(range)
+    }
+
+    fn insert(&mut self, i: usize, start: VirtAddr, pages: usize) -> Result<(), VirtAllocError> {
+        if self.len == MAX_RANGES {
+            return Err(VirtAllocError::TooManyRanges { addr: start });
+        }
+
+        for j in (i..self.len).rev() {
+            self.ranges[j + 1] = self.ranges[j];
+        }
+
+        self.ranges[i] = (start, pages);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn remove(&mut self, i: usize) {
+        for j in i..self.len - 1 {
+            self.ranges[j] = self.ranges[j + 1];
+        }
+
+        self.len -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn page(index: usize) -> Page {
+        Page::containing_addr(VirtAddr::new(index * PAGE_SIZE))
+    }
+
+    fn index(page: Page) -> usize {
+        page.start_addr().as_usize() / PAGE_SIZE
+    }
+
+    #[test]
+    fn alloc_leaves_guard_page() {
+        let mut allocator = VirtAllocator::new(page(16), page(31));
+
+        let a = allocator.alloc(3).unwrap();
+        assert_eq!((index(a.start()), index(a.end())), (17, 19));
+
+        let b = allocator.alloc(2).unwrap();
+        assert_eq!((index(b.start()), index(b.end())), (21, 22));
+    }
+
+    #[test]
+    fn alloc_refuses_empty_and_oversized_ranges() {
+        let mut allocator = VirtAllocator::new(page(16), page(31));
+
+        assert!(allocator.alloc(0).is_none());
+        assert!(allocator.alloc(16).is_none());
+
+        // 15 pages and the guard use up everything
+        let range = allocator.alloc(15).unwrap();
+        assert_eq!(allocator.len, 0);
+        assert!(allocator.alloc(1).is_none());
+
+        allocator.free(range).unwrap();
+        assert_eq!(allocator.ranges[..allocator.len], [(page(16).start_addr(), 16)]);
+    }
+
+    #[test]
+    fn free_merges_neighbours() {
+        let mut allocator = VirtAllocator::new(page(16), page(31));
+
+        let a = allocator.alloc(3).unwrap();
+        let b = allocator.alloc(3).unwrap();
+        let c = allocator.alloc(3).unwrap();
+
+        allocator.free(a).unwrap();
+        assert_eq!(allocator.len, 2);
+
+        // c touches the free tail
+        allocator.free(c).unwrap();
+        assert_eq!(allocator.ranges[..allocator.len], [(page(16).start_addr(), 4), (page(24).start_addr(), 8)]);
+
+        // b touches both
+        allocator.free(b).unwrap();
+        assert_eq!(allocator.ranges[..allocator.len], [(page(16).start_addr(), 16)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "freeing a free virtual range")]
+    fn double_free_panics() {
+        let mut allocator = VirtAllocator::new(page(16), page(31));
+
+        let a = allocator.alloc(3).unwrap();
+        let _b = allocator.alloc(3).unwrap();
+
+        allocator.free(a.clone()).unwrap();
+        allocator.free(a).unwrap();
+    }
+
+    #[test]
+    fn too_many_ranges_is_an_error() {
+        let mut allocator = VirtAllocator::new(page(16), page(16 + 5 * MAX_RANGES));
+
+        let ranges = (0..2 * MAX_RANGES + 1).map(|_| allocator.alloc(1).unwrap()).collect::<Vec<_>>();
+
+        // every other range, so none of them merge; the tail is already one free range
+        let mut isolated = ranges.into_iter().enumerate().filter(|&(i, _)| i % 2 == 0).map(|(_, r)| r);
+        isolated.by_ref().take(MAX_RANGES - 1).for_each(|r| allocator.free(r).unwrap());
+        assert_eq!(allocator.len, MAX_RANGES);
+
+        let range = isolated.next().unwrap();
+        match allocator.free(range.clone()) {
+            Err(VirtAllocError::TooManyRanges { addr }) => assert_eq!(addr, range.start().start_addr() - PAGE_SIZE),
+            Ok(()) => panic!("free past MAX_RANGES succeeded"),
+        }
+        assert_eq!(allocator.len, MAX_RANGES);
+    }
+}
@@ -0,0 +1,106 @@
+use memory::paging::{Mapper, Page, PageIter};
+
+/// Most regions sampled at once.
+const MAX_TRACKED: usize = 16;
+
+/// What a region's pages did between the last two samples.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WorkingSet {
+    /// Pages mapped at the last sample.
+    pub resident: usize,
+
+    /// Pages accessed since the sample before.
+    pub accessed: usize,
+
+    /// Pages written since the sample before.
+    pub dirty: usize,
+
+    /// Moving average of `accessed`, weighting each new sample by 1/4. The working set estimate.
+    pub average: usize,
+
+    pub samples: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Tracked {
+    start: Page,
+    end: Page,
+    working_set: WorkingSet,
+}
+
+/// Estimates the working sets of kernel regions from their accessed/dirty bits. Each `sample`
+/// covers the interval since the previous one, so it should be called at a steady rate.
+pub struct WorkingSetSampler {
+    regions: [Option<Tracked>; MAX_TRACKED],
+}
+
+impl WorkingSetSampler {
+    pub fn new() -> WorkingSetSampler {
+        WorkingSetSampler {
+            regions: [None; MAX_TRACKED],
+        }
+    }
+
+    /// Start sampling `pages`. Panics if too many regions are tracked.
+    pub fn track(&mut self, pages: PageIter) {
+        assert!(!pages.is_empty(), "tracking an empty region");
+
+        let slot = self.regions.iter_mut()
+            .find(|r| r.is_none())
+            .expect("too many working set regions");
+
+        *slot = Some(Tracked {
+            start: pages.start(),
+            end: pages.end(),
+            working_set: WorkingSet::default(),
+        });
+    }
+
+    /// Stop sampling the region starting at `start`, returning its last estimate.
+    pub fn untrack(&mut self, start: Page) -> Option<WorkingSet> {
+        self.regions.iter_mut()
+            .find(|r| r.map_or(false, |r| r.start == start))
+            .and_then(|r| r.take())
+            .map(|r| r.working_set)
+    }
+
+    /// The estimate for the region starting at `start`.
+    pub fn get(&self, start: Page) -> Option<WorkingSet> {
+        self.regions.iter()
+            .filter_map(|r| *r)
+            .find(|r| r.start == start)
+            .map(|r| r.working_set)
+    }
+
+    /// Scan every tracked region, clearing the bits for the next interval.
+    pub fn sample(&mut self, mapper: &mut Mapper) {
+        for region in self.regions.iter_mut().filter_map(|r| r.as_mut()) {
+            let (mut resident, mut accessed, mut dirty) = (0, 0, 0);
+
+            for usage in mapper.scan_and_clear(Page::range_inclusive(region.start, region.end)) {
+                resident += 1;
+
+                if usage.accessed {
+                    accessed += 1;
+                }
+
+                if usage.dirty {
+                    dirty += 1;
+                }
+            }
+
+            let ws = &mut region.working_set;
+
+            ws.average = if ws.samples == 0 {
+                accessed
+            } else {
+                (ws.average * 3 + accessed + 2) / 4
+            };
+
+            ws.resident = resident;
+            ws.accessed = accessed;
+            ws.dirty = dirty;
+            ws.samples += 1;
+        }
+    }
+}
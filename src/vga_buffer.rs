@@ -1,8 +1,11 @@
 use core::fmt;
+use core::mem;
 use core::ptr::Unique;
-use spin::Mutex;
+use spin::{Mutex, Once};
 use volatile::Volatile;
 
+use memory::{self, CacheType, MmioRegion, PhysAddr};
+
 const VGA_BASE: usize = 0xb8000;
 
 #[allow(dead_code)]
@@ -159,3 +162,17 @@ pub fn clear_screen() {
         println!();
     }
 }
+
+static MAPPING: Once<MmioRegion> = Once::new();
+
+/// Move the writer from the bootloader's identity mapping of the buffer to one in the kernel
+/// half, so address spaces don't have to share the lower-half entry it lives in. Needs the
+/// memory controller.
+pub fn remap() {
+    let region = MAPPING.call_once(|| {
+        memory::map_mmio(PhysAddr::new(VGA_BASE), mem::size_of::<Buffer>(), CacheType::WriteCombining)
+            .expect("unable to map VGA buffer")
+    });
+
+    WRITER.lock().buf = unsafe { Unique::new_unchecked(region.base().as_mut_ptr()) };
+}